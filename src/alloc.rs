@@ -0,0 +1,225 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+//ids below this in each client's range are left for groups and hand numbered nodes
+const FIRST_TEMP_ID: i32 = 1000;
+
+//Hands out node ids from the slice of the id space that belongs to one client.
+//scsynth splits nothing itself, so every client sharing a server has to keep
+//to range client_id * (2^31 / max_logins).
+#[derive(Debug)]
+pub struct NodeIdAllocator {
+    client_id: i32,
+    max_logins: i32,
+    offset: i32,
+    size: i32,
+    next: i32,
+    used: HashSet<i32>,
+    free: VecDeque<i32>,
+}
+
+impl NodeIdAllocator {
+    pub fn new(client_id: i32, max_logins: i32) -> NodeIdAllocator {
+        let max_logins = max_logins.max(1);
+        let size = ((1i64 << 31) / max_logins as i64).min(i32::max_value() as i64) as i32;
+        let offset = (client_id as i64 * size as i64).min(i32::max_value() as i64) as i32;
+        NodeIdAllocator {
+            client_id: client_id,
+            max_logins: max_logins,
+            offset: offset,
+            size: size,
+            next: offset + FIRST_TEMP_ID,
+            used: HashSet::new(),
+            free: VecDeque::new(),
+        }
+    }
+
+    pub fn client_id(&self) -> i32 {
+        self.client_id
+    }
+
+    pub fn max_logins(&self) -> i32 {
+        self.max_logins
+    }
+
+    //the group sc_start creates for this client, 1 for client 0 as in sclang
+    pub fn default_group(&self) -> i32 {
+        self.offset + 1
+    }
+
+    pub fn contains(&self, id: i32) -> bool {
+        id >= self.offset && (id - self.offset) < self.size
+    }
+
+    //Ids freed by /n_end are reused oldest first. Once the range is used up the
+    //counter wraps around, skipping ids still in use.
+    pub fn alloc(&mut self) -> i32 {
+        let id = match self.free.pop_front() {
+            Some(id) => id,
+            None => loop {
+                let id = self.next;
+                self.next = if self.next - self.offset >= self.size - 1 {
+                    self.offset + FIRST_TEMP_ID
+                } else {
+                    self.next + 1
+                };
+                if !self.used.contains(&id) {
+                    break id;
+                }
+            },
+        };
+        self.used.insert(id);
+        id
+    }
+
+    //ignores ids this allocator did not hand out, or already got back
+    pub fn free(&mut self, id: i32) {
+        if self.used.remove(&id) {
+            self.free.push_back(id);
+        }
+    }
+}
+
+//When nodes ended, so a handle can tell its node from a later one that got the
+//same id. The clock moves on with every /n_end; a handle keeps the time it was
+//made and its node has ended once the id ends after that time.
+#[derive(Debug)]
+pub struct NodeEnds {
+    clock: u64,
+    ended: HashMap<i32, u64>,
+    all_ended: u64,
+}
+
+impl NodeEnds {
+    pub fn new() -> NodeEnds {
+        NodeEnds {
+            clock: 0,
+            ended: HashMap::new(),
+            all_ended: 0,
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.clock
+    }
+
+    pub fn end(&mut self, id: i32) {
+        self.clock += 1;
+        self.ended.insert(id, self.clock);
+    }
+
+    //after a server restart no node from before is left
+    pub fn end_all(&mut self) {
+        self.clock += 1;
+        self.all_ended = self.clock;
+        self.ended.clear();
+    }
+
+    pub fn has_ended(&self, id: i32, since: u64) -> bool {
+        self.all_ended > since || self.ended.get(&id).map_or(false, |&time| time > since)
+    }
+}
+
+//First fit allocator for runs of consecutive numbers, for buffers and buses
+//where one multichannel object takes several neighbours.
+#[derive(Debug)]
+pub struct BlockAllocator {
+    offset: i32,
+    size: i32,
+    //start and length of every block in use, sorted by start
+    used: Vec<(i32, i32)>,
+}
+
+impl BlockAllocator {
+    pub fn new(offset: i32, size: i32) -> BlockAllocator {
+        BlockAllocator {
+            offset: offset,
+            size: size,
+            used: Vec::new(),
+        }
+    }
+
+    //the share of first..total that belongs to one client, as sclang splits them
+    pub fn client_range(first: i32, total: i32, client_id: i32, max_logins: i32) -> BlockAllocator {
+        let size = (total - first) / max_logins.max(1);
+        BlockAllocator::new(first + client_id * size, size)
+    }
+
+    pub fn alloc(&mut self, count: i32) -> Option<i32> {
+        let mut pos = self.offset;
+        let mut index = self.used.len();
+        for (i, &(start, len)) in self.used.iter().enumerate() {
+            if start - pos >= count {
+                index = i;
+                break;
+            }
+            pos = start + len;
+        }
+        if index == self.used.len() && self.offset + self.size - pos < count {
+            return None;
+        }
+        self.used.insert(index, (pos, count));
+        Some(pos)
+    }
+
+    //frees the block starting at start, returns false if there is none
+    pub fn free(&mut self, start: i32) -> bool {
+        match self.used.iter().position(|x| x.0 == start) {
+            Some(pos) => {
+                self.used.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[test]
+fn test_node_id_allocator() {
+    let mut alloc = NodeIdAllocator::new(0, 1);
+    assert_eq!(alloc.default_group(), 1);
+    assert_eq!(alloc.alloc(), 1000);
+    assert_eq!(alloc.alloc(), 1001);
+    alloc.free(1000);
+    alloc.free(1000);
+    alloc.free(1);
+    assert_eq!(alloc.alloc(), 1000);
+    assert_eq!(alloc.alloc(), 1002);
+
+    let mut alloc = NodeIdAllocator::new(3, 4);
+    assert_eq!(alloc.default_group(), 3 * (1 << 29) + 1);
+    let id = alloc.alloc();
+    assert_eq!(id, 3 * (1 << 29) + 1000);
+    assert!(alloc.contains(id));
+    assert!(!NodeIdAllocator::new(2, 4).contains(id));
+}
+
+#[test]
+fn test_block_allocator() {
+    let mut alloc = BlockAllocator::client_range(0, 1024, 1, 4);
+    assert_eq!(alloc.alloc(2), Some(256));
+    assert_eq!(alloc.alloc(1), Some(258));
+    assert_eq!(alloc.alloc(4), Some(259));
+    assert!(alloc.free(256));
+    assert!(!alloc.free(257));
+    assert_eq!(alloc.alloc(3), Some(263));
+    assert_eq!(alloc.alloc(2), Some(256));
+    assert_eq!(alloc.alloc(250), None);
+    assert_eq!(alloc.alloc(512 - 266), Some(266));
+    //audio buses after 8 outputs and 8 inputs
+    assert_eq!(BlockAllocator::client_range(16, 1024, 0, 1).alloc(2), Some(16));
+}
+
+#[test]
+fn test_node_ends() {
+    let mut ends = NodeEnds::new();
+    let since = ends.now();
+    assert!(!ends.has_ended(1000, since));
+    ends.end(1000);
+    assert!(ends.has_ended(1000, since));
+    assert!(!ends.has_ended(1001, since));
+    //a handle made after the id ended gets a node of its own
+    assert!(!ends.has_ended(1000, ends.now()));
+    let since = ends.now();
+    ends.end_all();
+    assert!(ends.has_ended(1001, since));
+}
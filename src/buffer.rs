@@ -0,0 +1,299 @@
+use osc::*;
+use commands::*;
+use server::{Server, ServerError};
+
+//floats per /b_setn or /b_getn message, keeps each packet under 8 KB
+const CHUNK_SIZE: i32 = 1600;
+
+//How /b_gen's sine and cheby fills treat the buffer, all on by default as in sclang
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct GenFlags {
+    pub normalize: bool,
+    //writes the table in wavetable format, for Osc, VOsc and Shaper
+    pub wavetable: bool,
+    //clears the buffer first instead of adding to what is there
+    pub clear: bool,
+}
+
+impl Default for GenFlags {
+    fn default() -> Self {
+        GenFlags {
+            normalize: true,
+            wavetable: true,
+            clear: true,
+        }
+    }
+}
+
+impl GenFlags {
+    fn bits(&self) -> i32 {
+        (self.normalize as i32) | (self.wavetable as i32) << 1 | (self.clear as i32) << 2
+    }
+}
+
+fn floats(values: &[f32]) -> Vec<Datum> {
+    values.iter().map(|x| Datum::Float(*x)).collect()
+}
+
+//A buffer on the server. The handle keeps the size last reported by /b_info;
+//dropping it leaves the buffer allocated, free gives the number back.
+pub struct Buffer {
+    server: Server,
+    bufnum: i32,
+    frames: i32,
+    channels: i32,
+    sample_rate: f32,
+}
+
+impl Buffer {
+    pub fn alloc(server: &Server, frames: i32, channels: i32) -> Result<Buffer, ServerError> {
+        let mut buffer = Buffer::from_bufnum(server, server.alloc_buffers(1)?);
+        buffer.frames = frames;
+        buffer.channels = channels;
+        buffer.allocated(ServerCommand::BAlloc {
+            bufnum: buffer.bufnum,
+            frames: frames,
+            channels: channels,
+        })?;
+        Ok(buffer)
+    }
+
+    //allocates a buffer sized to the file, frames -1 reads to its end
+    pub fn read(server: &Server, path: &str, start: i32, frames: i32) -> Result<Buffer, ServerError> {
+        let mut buffer = Buffer::from_bufnum(server, server.alloc_buffers(1)?);
+        buffer.allocated(ServerCommand::BAllocRead {
+            bufnum: buffer.bufnum,
+            path: path.to_string(),
+            start: start,
+            frames: frames,
+        })?;
+        buffer.query()?;
+        Ok(buffer)
+    }
+
+    //wraps a buffer number this client did not allocate, size unknown until query
+    pub fn from_bufnum(server: &Server, bufnum: i32) -> Buffer {
+        Buffer {
+            server: server.clone(),
+            bufnum: bufnum,
+            frames: 0,
+            channels: 0,
+            sample_rate: 0.0,
+        }
+    }
+
+    //gives the number back when the server refused to allocate
+    fn allocated(&self, command: ServerCommand) -> Result<(), ServerError> {
+        let result = self.server.send_command(&command);
+        if result.is_err() {
+            self.server.free_buffers(self.bufnum);
+        }
+        result
+    }
+
+    pub fn bufnum(&self) -> i32 {
+        self.bufnum
+    }
+
+    pub fn frames(&self) -> i32 {
+        self.frames
+    }
+
+    pub fn channels(&self) -> i32 {
+        self.channels
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    //reads a file into the existing buffer, starting at buf_start
+    pub fn read_file(&self, path: &str, file_start: i32, frames: i32, buf_start: i32) -> Result<(), ServerError> {
+        self.server.send_command(&ServerCommand::BRead {
+            bufnum: self.bufnum,
+            path: path.to_string(),
+            file_start: file_start,
+            frames: frames,
+            buf_start: buf_start,
+            leave_open: false,
+        })
+    }
+
+    //header_format is e.g. "wav" or "aiff", sample_format "int16" or "float"
+    pub fn write(&self, path: &str, header_format: &str, sample_format: &str) -> Result<(), ServerError> {
+        self.server.send_command(&ServerCommand::BWrite {
+            bufnum: self.bufnum,
+            path: path.to_string(),
+            header_format: header_format.to_string(),
+            sample_format: sample_format.to_string(),
+            frames: -1,
+            start: 0,
+            leave_open: false,
+        })
+    }
+
+    pub fn zero(&self) -> Result<(), ServerError> {
+        self.server.send_command(&ServerCommand::BZero { bufnum: self.bufnum })
+    }
+
+    //asks the server for the buffer's size and updates the handle
+    pub fn query(&mut self) -> Result<BufferInfo, ServerError> {
+        let bufnum = self.bufnum;
+        let rx = self.server.expect_reply(move |reply| {
+            reply.name == "/b_info" && reply.l_datum.get(0) == Some(&Datum::Int(bufnum))
+        });
+        self.server.send_command(&ServerCommand::BQuery { bufnums: vec![bufnum] })?;
+        let reply = self.server.wait_reply(&rx, "/b_query")?;
+        if let ServerReply::BufferInfo(infos) = ServerReply::from_message(&reply)? {
+            if let Some(info) = infos.into_iter().find(|x| x.bufnum == bufnum) {
+                self.frames = info.frames;
+                self.channels = info.channels;
+                self.sample_rate = info.sample_rate;
+                return Ok(info);
+            }
+        }
+        Err(ServerError::Osc(OscError::UnexpectedArguments(reply.name)))
+    }
+
+    //Writes samples from index start on, interleaved for multichannel buffers.
+    //Returns once the server has taken all of them.
+    pub fn set(&self, start: i32, samples: &[f32]) -> Result<(), ServerError> {
+        for (index, count) in chunk_ranges(start, samples.len() as i32) {
+            let from = (index - start) as usize;
+            self.server.send_command(&ServerCommand::BSetn {
+                bufnum: self.bufnum,
+                ranges: vec![(index, samples[from..from + count as usize].to_vec())],
+            })?;
+        }
+        self.server.sync()
+    }
+
+    //reads count samples from index start on, one /b_getn per chunk
+    pub fn get(&self, start: i32, count: i32) -> Result<Vec<f32>, ServerError> {
+        let mut out = Vec::with_capacity(count.max(0) as usize);
+        for (index, count) in chunk_ranges(start, count) {
+            let bufnum = self.bufnum;
+            let rx = self.server.expect_reply(move |reply| {
+                reply.name == "/b_setn" && reply.l_datum.get(0) == Some(&Datum::Int(bufnum)) &&
+                reply.l_datum.get(1) == Some(&Datum::Int(index))
+            });
+            self.server.send_command(&ServerCommand::BGetn {
+                bufnum: bufnum,
+                ranges: vec![(index, count)],
+            })?;
+            let reply = self.server.wait_reply(&rx, "/b_getn")?;
+            match ServerReply::from_message(&reply)? {
+                ServerReply::BSetn { ranges, .. } => {
+                    for (_, values) in ranges {
+                        out.extend(values);
+                    }
+                }
+                _ => return Err(ServerError::Osc(OscError::UnexpectedArguments(reply.name))),
+            }
+        }
+        Ok(out)
+    }
+
+    fn gen(&self, name: &str, args: Vec<Datum>) -> Result<(), ServerError> {
+        self.server.send_command(&ServerCommand::BGen {
+            bufnum: self.bufnum,
+            name: name.to_string(),
+            args: args,
+        })
+    }
+
+    //harmonics 1, 2, 3... with the given amplitudes
+    pub fn sine1(&self, amps: &[f32], flags: GenFlags) -> Result<(), ServerError> {
+        let mut args = vec![Datum::Int(flags.bits())];
+        args.extend(floats(amps));
+        self.gen("sine1", args)
+    }
+
+    //(frequency, amplitude) pairs, frequencies in cycles per buffer
+    pub fn sine2(&self, partials: &[(f32, f32)], flags: GenFlags) -> Result<(), ServerError> {
+        let mut args = vec![Datum::Int(flags.bits())];
+        for &(freq, amp) in partials {
+            args.extend(floats(&[freq, amp]));
+        }
+        self.gen("sine2", args)
+    }
+
+    //(frequency, amplitude, phase) triples
+    pub fn sine3(&self, partials: &[(f32, f32, f32)], flags: GenFlags) -> Result<(), ServerError> {
+        let mut args = vec![Datum::Int(flags.bits())];
+        for &(freq, amp, phase) in partials {
+            args.extend(floats(&[freq, amp, phase]));
+        }
+        self.gen("sine3", args)
+    }
+
+    //sum of Chebyshev polynomials for waveshaping with Shaper
+    pub fn cheby(&self, amps: &[f32], flags: GenFlags) -> Result<(), ServerError> {
+        let mut args = vec![Datum::Int(flags.bits())];
+        args.extend(floats(amps));
+        self.gen("cheby", args)
+    }
+
+    //copies frames from source into this buffer, frames -1 copies as much as fits
+    pub fn copy_from(&self, dest_start: i32, source: &Buffer, source_start: i32, frames: i32) -> Result<(), ServerError> {
+        self.gen("copy", vec![Datum::Int(dest_start), Datum::Int(source.bufnum),
+                              Datum::Int(source_start), Datum::Int(frames)])
+    }
+
+    pub fn free(self) -> Result<(), ServerError> {
+        self.server.send_command(&ServerCommand::BFree { bufnum: self.bufnum })?;
+        self.server.free_buffers(self.bufnum);
+        Ok(())
+    }
+}
+
+//splits count samples from start into (index, count) pieces of CHUNK_SIZE
+fn chunk_ranges(start: i32, count: i32) -> Vec<(i32, i32)> {
+    let mut out = Vec::new();
+    let mut index = start;
+    while index < start + count {
+        let size = CHUNK_SIZE.min(start + count - index);
+        out.push((index, size));
+        index += size;
+    }
+    out
+}
+
+#[test]
+fn test_gen_flags() {
+    assert_eq!(GenFlags::default().bits(), 7);
+    assert_eq!(GenFlags { normalize: false, ..GenFlags::default() }.bits(), 6);
+}
+
+#[test]
+fn test_chunk_ranges() {
+    assert_eq!(chunk_ranges(0, 0), vec![]);
+    assert_eq!(chunk_ranges(10, 5), vec![(10, 5)]);
+    assert_eq!(chunk_ranges(100, 2 * CHUNK_SIZE + 1),
+               vec![(100, CHUNK_SIZE), (100 + CHUNK_SIZE, CHUNK_SIZE), (100 + 2 * CHUNK_SIZE, 1)]);
+}
+
+#[test]
+fn test_set_get_chunks() {
+    use server::{fake_options, FakeScsynth};
+    let fake = FakeScsynth::new();
+    let server = Server::new(fake_options(&fake)).unwrap();
+    let buffer = Buffer::from_bufnum(&server, 0);
+    let count = 2 * CHUNK_SIZE + CHUNK_SIZE / 2;
+    let samples: Vec<f32> = (0..count).map(|x| x as f32).collect();
+    buffer.set(10, &samples).unwrap();
+    assert_eq!(buffer.get(10, count).unwrap(), samples);
+    let mut ranges = Vec::new();
+    loop {
+        let message = fake.next_message();
+        match (message.name.as_str(), message.l_datum.get(1), message.l_datum.get(2)) {
+            ("/b_setn", Some(&Datum::Int(index)), Some(&Datum::Int(count))) => ranges.push((index, count)),
+            _ => break,
+        }
+    }
+    assert_eq!(ranges, vec![(10, CHUNK_SIZE), (10 + CHUNK_SIZE, CHUNK_SIZE), (10 + 2 * CHUNK_SIZE, CHUNK_SIZE / 2)]);
+}
@@ -0,0 +1,142 @@
+use osc::*;
+use commands::*;
+use sc3::Rate;
+use server::{Server, ServerError};
+
+//A run of consecutive audio or control buses. Like Buffer, dropping the handle
+//keeps the buses taken, free gives them back.
+pub struct Bus {
+    server: Server,
+    rate: Rate,
+    index: i32,
+    channels: i32,
+}
+
+impl Bus {
+    pub fn control(server: &Server, channels: i32) -> Result<Bus, ServerError> {
+        Bus::alloc(server, Rate::RateKr, channels)
+    }
+
+    //allocated above the hardware output and input channels
+    pub fn audio(server: &Server, channels: i32) -> Result<Bus, ServerError> {
+        Bus::alloc(server, Rate::RateAr, channels)
+    }
+
+    fn alloc(server: &Server, rate: Rate, channels: i32) -> Result<Bus, ServerError> {
+        let index = server.alloc_buses(rate, channels)?;
+        Ok(Bus::from_index(server, rate, index, channels))
+    }
+
+    //wraps buses this client did not allocate, such as the hardware outputs at 0
+    pub fn from_index(server: &Server, rate: Rate, index: i32, channels: i32) -> Bus {
+        Bus {
+            server: server.clone(),
+            rate: rate,
+            index: index,
+            channels: channels,
+        }
+    }
+
+    pub fn rate(&self) -> Rate {
+        self.rate
+    }
+
+    pub fn index(&self) -> i32 {
+        self.index
+    }
+
+    pub fn channels(&self) -> i32 {
+        self.channels
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    //The value commands below only work on control buses; scsynth keeps no
+    //readable value for audio buses, so on those they fail without sending.
+    fn control_only(&self) -> Result<(), ServerError> {
+        if self.rate != Rate::RateKr {
+            return Err(ServerError::NotControlBus(self.index));
+        }
+        Ok(())
+    }
+
+    pub fn set(&self, value: f32) -> Result<(), ServerError> {
+        self.control_only()?;
+        self.server.send_command(&ServerCommand::CSet { buses: vec![(self.index, value)] })
+    }
+
+    //one value per channel, from the first channel on
+    pub fn setn(&self, values: &[f32]) -> Result<(), ServerError> {
+        self.control_only()?;
+        self.server.send_command(&ServerCommand::CSetn { ranges: vec![(self.index, values.to_vec())] })
+    }
+
+    //sets every channel to value
+    pub fn fill(&self, value: f32) -> Result<(), ServerError> {
+        self.control_only()?;
+        self.server.send_command(&ServerCommand::CFill { ranges: vec![(self.index, self.channels, value)] })
+    }
+
+    //the value of the first channel
+    pub fn get(&self) -> Result<f32, ServerError> {
+        self.control_only()?;
+        let index = self.index;
+        let rx = self.server.expect_reply(move |reply| {
+            reply.name == "/c_set" && reply.l_datum.get(0) == Some(&Datum::Int(index))
+        });
+        self.server.send_command(&ServerCommand::CGet { buses: vec![index] })?;
+        let reply = self.server.wait_reply(&rx, "/c_get")?;
+        match ServerReply::from_message(&reply)? {
+            ServerReply::CSet { ref buses } if !buses.is_empty() => Ok(buses[0].1),
+            _ => Err(ServerError::Osc(OscError::UnexpectedArguments(reply.name))),
+        }
+    }
+
+    //the values of all channels
+    pub fn getn(&self) -> Result<Vec<f32>, ServerError> {
+        self.control_only()?;
+        let index = self.index;
+        let rx = self.server.expect_reply(move |reply| {
+            reply.name == "/c_setn" && reply.l_datum.get(0) == Some(&Datum::Int(index))
+        });
+        self.server.send_command(&ServerCommand::CGetn { ranges: vec![(index, self.channels)] })?;
+        let reply = self.server.wait_reply(&rx, "/c_getn")?;
+        match ServerReply::from_message(&reply)? {
+            ServerReply::CSetn { mut ranges } if !ranges.is_empty() => Ok(ranges.remove(0).1),
+            _ => Err(ServerError::Osc(OscError::UnexpectedArguments(reply.name))),
+        }
+    }
+
+    pub fn free(self) -> Result<(), ServerError> {
+        if !self.server.free_buses(self.rate, self.index) {
+            return Err(ServerError::NotAllocated(self.index));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_bus() {
+    use server::{fake_options, FakeScsynth};
+    let server = Server::new(fake_options(&FakeScsynth::new())).unwrap();
+    let audio = Bus::audio(&server, 2).unwrap();
+    assert_eq!(audio.index(), 16);
+    assert_eq!(Bus::audio(&server, 1).unwrap().index(), 18);
+    match audio.set(1.0) {
+        Err(ServerError::NotControlBus(index)) => assert_eq!(index, 16),
+        other => panic!("expected an error, got {:?}", other),
+    }
+    assert!(audio.getn().is_err());
+    audio.free().unwrap();
+    assert_eq!(Bus::audio(&server, 2).unwrap().index(), 16);
+    match Bus::from_index(&server, Rate::RateKr, 100, 1).free() {
+        Err(ServerError::NotAllocated(index)) => assert_eq!(index, 100),
+        other => panic!("expected an error, got {:?}", other),
+    }
+    let control = Bus::control(&server, 3).unwrap();
+    assert_eq!(control.index(), 0);
+    control.setn(&[0.5, 0.5, 0.5]).unwrap();
+    assert_eq!(control.getn().unwrap(), vec![0.5, 0.5, 0.5]);
+}
@@ -0,0 +1,945 @@
+use osc::*;
+use std::fmt;
+
+//Where a new node goes relative to its target
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AddAction {
+    AddToHead = 0,
+    AddToTail = 1,
+    AddBefore = 2,
+    AddAfter = 3,
+    AddReplace = 4,
+}
+
+//Synth controls are addressed by index or by name
+#[derive(Clone, PartialEq, Debug)]
+pub enum ControlId {
+    Index(i32),
+    Name(String),
+}
+
+impl<'a> From<&'a str> for ControlId {
+    fn from(name: &'a str) -> ControlId {
+        ControlId::Name(name.to_string())
+    }
+}
+
+impl From<String> for ControlId {
+    fn from(name: String) -> ControlId {
+        ControlId::Name(name)
+    }
+}
+
+impl From<i32> for ControlId {
+    fn from(index: i32) -> ControlId {
+        ControlId::Index(index)
+    }
+}
+
+fn control_datum(control: &ControlId) -> Datum {
+    match control {
+        ControlId::Index(index) => Datum::Int(*index),
+        ControlId::Name(name) => Datum::Str(name.clone()),
+    }
+}
+
+fn bool_datum(flag: bool) -> Datum {
+    Datum::Int(if flag { 1 } else { 0 })
+}
+
+//One variant per command of the scsynth server command reference
+#[derive(Clone, PartialEq, Debug)]
+pub enum ServerCommand {
+    Quit,
+    Notify { on: bool, client_id: Option<i32> },
+    Status,
+    Cmd { name: String, args: Vec<Datum> },
+    DumpOsc { code: i32 },
+    Sync { id: i32 },
+    ClearSched,
+    Error { mode: i32 },
+    Version,
+
+    DRecv { data: Vec<u8> },
+    DLoad { path: String },
+    DLoadDir { path: String },
+    DFree { names: Vec<String> },
+
+    NFree { ids: Vec<i32> },
+    NRun { nodes: Vec<(i32, bool)> },
+    NSet { id: i32, controls: Vec<(ControlId, f32)> },
+    NSetn { id: i32, controls: Vec<(ControlId, Vec<f32>)> },
+    NFill { id: i32, controls: Vec<(ControlId, i32, f32)> },
+    NMap { id: i32, controls: Vec<(ControlId, i32)> },
+    NMapn { id: i32, controls: Vec<(ControlId, i32, i32)> },
+    NMapa { id: i32, controls: Vec<(ControlId, i32)> },
+    NMapan { id: i32, controls: Vec<(ControlId, i32, i32)> },
+    NBefore { pairs: Vec<(i32, i32)> },
+    NAfter { pairs: Vec<(i32, i32)> },
+    NQuery { ids: Vec<i32> },
+    NTrace { ids: Vec<i32> },
+    NOrder { add_action: AddAction, target: i32, ids: Vec<i32> },
+
+    SNew { def_name: String, id: i32, add_action: AddAction, target: i32, controls: Vec<(ControlId, f32)> },
+    SGet { id: i32, controls: Vec<ControlId> },
+    SGetn { id: i32, controls: Vec<(ControlId, i32)> },
+    SNoid { ids: Vec<i32> },
+
+    GNew { groups: Vec<(i32, AddAction, i32)> },
+    PNew { groups: Vec<(i32, AddAction, i32)> },
+    GHead { pairs: Vec<(i32, i32)> },
+    GTail { pairs: Vec<(i32, i32)> },
+    GFreeAll { ids: Vec<i32> },
+    GDeepFree { ids: Vec<i32> },
+    GDumpTree { groups: Vec<(i32, bool)> },
+    GQueryTree { groups: Vec<(i32, bool)> },
+
+    UCmd { node: i32, ugen_index: i32, name: String, args: Vec<Datum> },
+
+    BAlloc { bufnum: i32, frames: i32, channels: i32 },
+    BAllocRead { bufnum: i32, path: String, start: i32, frames: i32 },
+    BAllocReadChannel { bufnum: i32, path: String, start: i32, frames: i32, channels: Vec<i32> },
+    BRead { bufnum: i32, path: String, file_start: i32, frames: i32, buf_start: i32, leave_open: bool },
+    BReadChannel { bufnum: i32, path: String, file_start: i32, frames: i32, buf_start: i32,
+                   leave_open: bool, channels: Vec<i32> },
+    BWrite { bufnum: i32, path: String, header_format: String, sample_format: String, frames: i32,
+             start: i32, leave_open: bool },
+    BFree { bufnum: i32 },
+    BZero { bufnum: i32 },
+    BSet { bufnum: i32, samples: Vec<(i32, f32)> },
+    BSetn { bufnum: i32, ranges: Vec<(i32, Vec<f32>)> },
+    BFill { bufnum: i32, ranges: Vec<(i32, i32, f32)> },
+    BGen { bufnum: i32, name: String, args: Vec<Datum> },
+    BClose { bufnum: i32 },
+    BQuery { bufnums: Vec<i32> },
+    BGet { bufnum: i32, indices: Vec<i32> },
+    BGetn { bufnum: i32, ranges: Vec<(i32, i32)> },
+
+    CSet { buses: Vec<(i32, f32)> },
+    CSetn { ranges: Vec<(i32, Vec<f32>)> },
+    CFill { ranges: Vec<(i32, i32, f32)> },
+    CGet { buses: Vec<i32> },
+    CGetn { ranges: Vec<(i32, i32)> },
+}
+
+fn ints(ids: &Vec<i32>) -> Vec<Datum> {
+    ids.iter().map(|x| Datum::Int(*x)).collect()
+}
+
+fn int_pairs(pairs: &Vec<(i32, i32)>) -> Vec<Datum> {
+    let mut out = Vec::new();
+    for &(a, b) in pairs {
+        out.push(Datum::Int(a));
+        out.push(Datum::Int(b));
+    }
+    out
+}
+
+fn index_values(pairs: &Vec<(i32, f32)>) -> Vec<Datum> {
+    let mut out = Vec::new();
+    for &(index, value) in pairs {
+        out.push(Datum::Int(index));
+        out.push(Datum::Float(value));
+    }
+    out
+}
+
+fn index_ranges(ranges: &Vec<(i32, Vec<f32>)>) -> Vec<Datum> {
+    let mut out = Vec::new();
+    for &(ref start, ref values) in ranges {
+        out.push(Datum::Int(*start));
+        out.push(Datum::Int(values.len() as i32));
+        out.extend(values.iter().map(|x| Datum::Float(*x)));
+    }
+    out
+}
+
+fn index_fills(ranges: &Vec<(i32, i32, f32)>) -> Vec<Datum> {
+    let mut out = Vec::new();
+    for &(start, count, value) in ranges {
+        out.push(Datum::Int(start));
+        out.push(Datum::Int(count));
+        out.push(Datum::Float(value));
+    }
+    out
+}
+
+fn control_values(controls: &Vec<(ControlId, f32)>) -> Vec<Datum> {
+    let mut out = Vec::new();
+    for &(ref control, value) in controls {
+        out.push(control_datum(control));
+        out.push(Datum::Float(value));
+    }
+    out
+}
+
+fn control_ints(controls: &Vec<(ControlId, i32)>) -> Vec<Datum> {
+    let mut out = Vec::new();
+    for &(ref control, value) in controls {
+        out.push(control_datum(control));
+        out.push(Datum::Int(value));
+    }
+    out
+}
+
+fn control_int_pairs(controls: &Vec<(ControlId, i32, i32)>) -> Vec<Datum> {
+    let mut out = Vec::new();
+    for &(ref control, a, b) in controls {
+        out.push(control_datum(control));
+        out.push(Datum::Int(a));
+        out.push(Datum::Int(b));
+    }
+    out
+}
+
+fn group_placements(groups: &Vec<(i32, AddAction, i32)>) -> Vec<Datum> {
+    let mut out = Vec::new();
+    for &(id, add_action, target) in groups {
+        out.push(Datum::Int(id));
+        out.push(Datum::Int(add_action as i32));
+        out.push(Datum::Int(target));
+    }
+    out
+}
+
+fn group_flags(groups: &Vec<(i32, bool)>) -> Vec<Datum> {
+    let mut out = Vec::new();
+    for &(id, flag) in groups {
+        out.push(Datum::Int(id));
+        out.push(bool_datum(flag));
+    }
+    out
+}
+
+impl ServerCommand {
+    pub fn address(&self) -> &'static str {
+        match self {
+            ServerCommand::Quit => "/quit",
+            ServerCommand::Notify { .. } => "/notify",
+            ServerCommand::Status => "/status",
+            ServerCommand::Cmd { .. } => "/cmd",
+            ServerCommand::DumpOsc { .. } => "/dumpOSC",
+            ServerCommand::Sync { .. } => "/sync",
+            ServerCommand::ClearSched => "/clearSched",
+            ServerCommand::Error { .. } => "/error",
+            ServerCommand::Version => "/version",
+            ServerCommand::DRecv { .. } => "/d_recv",
+            ServerCommand::DLoad { .. } => "/d_load",
+            ServerCommand::DLoadDir { .. } => "/d_loadDir",
+            ServerCommand::DFree { .. } => "/d_free",
+            ServerCommand::NFree { .. } => "/n_free",
+            ServerCommand::NRun { .. } => "/n_run",
+            ServerCommand::NSet { .. } => "/n_set",
+            ServerCommand::NSetn { .. } => "/n_setn",
+            ServerCommand::NFill { .. } => "/n_fill",
+            ServerCommand::NMap { .. } => "/n_map",
+            ServerCommand::NMapn { .. } => "/n_mapn",
+            ServerCommand::NMapa { .. } => "/n_mapa",
+            ServerCommand::NMapan { .. } => "/n_mapan",
+            ServerCommand::NBefore { .. } => "/n_before",
+            ServerCommand::NAfter { .. } => "/n_after",
+            ServerCommand::NQuery { .. } => "/n_query",
+            ServerCommand::NTrace { .. } => "/n_trace",
+            ServerCommand::NOrder { .. } => "/n_order",
+            ServerCommand::SNew { .. } => "/s_new",
+            ServerCommand::SGet { .. } => "/s_get",
+            ServerCommand::SGetn { .. } => "/s_getn",
+            ServerCommand::SNoid { .. } => "/s_noid",
+            ServerCommand::GNew { .. } => "/g_new",
+            ServerCommand::PNew { .. } => "/p_new",
+            ServerCommand::GHead { .. } => "/g_head",
+            ServerCommand::GTail { .. } => "/g_tail",
+            ServerCommand::GFreeAll { .. } => "/g_freeAll",
+            ServerCommand::GDeepFree { .. } => "/g_deepFree",
+            ServerCommand::GDumpTree { .. } => "/g_dumpTree",
+            ServerCommand::GQueryTree { .. } => "/g_queryTree",
+            ServerCommand::UCmd { .. } => "/u_cmd",
+            ServerCommand::BAlloc { .. } => "/b_alloc",
+            ServerCommand::BAllocRead { .. } => "/b_allocRead",
+            ServerCommand::BAllocReadChannel { .. } => "/b_allocReadChannel",
+            ServerCommand::BRead { .. } => "/b_read",
+            ServerCommand::BReadChannel { .. } => "/b_readChannel",
+            ServerCommand::BWrite { .. } => "/b_write",
+            ServerCommand::BFree { .. } => "/b_free",
+            ServerCommand::BZero { .. } => "/b_zero",
+            ServerCommand::BSet { .. } => "/b_set",
+            ServerCommand::BSetn { .. } => "/b_setn",
+            ServerCommand::BFill { .. } => "/b_fill",
+            ServerCommand::BGen { .. } => "/b_gen",
+            ServerCommand::BClose { .. } => "/b_close",
+            ServerCommand::BQuery { .. } => "/b_query",
+            ServerCommand::BGet { .. } => "/b_get",
+            ServerCommand::BGetn { .. } => "/b_getn",
+            ServerCommand::CSet { .. } => "/c_set",
+            ServerCommand::CSetn { .. } => "/c_setn",
+            ServerCommand::CFill { .. } => "/c_fill",
+            ServerCommand::CGet { .. } => "/c_get",
+            ServerCommand::CGetn { .. } => "/c_getn",
+        }
+    }
+
+    //Asynchronous commands are answered with /done or /fail once the server has
+    //finished them in its non real time thread
+    pub fn is_async(&self) -> bool {
+        match self {
+            ServerCommand::Quit | ServerCommand::Notify { .. } |
+            ServerCommand::DRecv { .. } | ServerCommand::DLoad { .. } | ServerCommand::DLoadDir { .. } |
+            ServerCommand::BAlloc { .. } | ServerCommand::BAllocRead { .. } |
+            ServerCommand::BAllocReadChannel { .. } | ServerCommand::BRead { .. } |
+            ServerCommand::BReadChannel { .. } | ServerCommand::BWrite { .. } |
+            ServerCommand::BFree { .. } | ServerCommand::BZero { .. } | ServerCommand::BGen { .. } |
+            ServerCommand::BClose { .. } => true,
+            _ => false,
+        }
+    }
+
+    fn arguments(&self) -> Vec<Datum> {
+        match self {
+            ServerCommand::Quit | ServerCommand::Status | ServerCommand::ClearSched |
+            ServerCommand::Version => vec![],
+            ServerCommand::Notify { on, client_id } => {
+                let mut out = vec![bool_datum(*on)];
+                if let Some(id) = client_id {
+                    out.push(Datum::Int(*id));
+                }
+                out
+            }
+            ServerCommand::Cmd { name, args } => {
+                let mut out = vec![Datum::Str(name.clone())];
+                out.extend(args.clone());
+                out
+            }
+            ServerCommand::DumpOsc { code } => vec![Datum::Int(*code)],
+            ServerCommand::Sync { id } => vec![Datum::Int(*id)],
+            ServerCommand::Error { mode } => vec![Datum::Int(*mode)],
+
+            ServerCommand::DRecv { data } => vec![Datum::Blob(data.clone())],
+            ServerCommand::DLoad { path } => vec![Datum::Str(path.clone())],
+            ServerCommand::DLoadDir { path } => vec![Datum::Str(path.clone())],
+            ServerCommand::DFree { names } => names.iter().map(|x| Datum::Str(x.clone())).collect(),
+
+            ServerCommand::NFree { ids } => ints(ids),
+            ServerCommand::NRun { nodes } => {
+                let mut out = Vec::new();
+                for &(id, run) in nodes {
+                    out.push(Datum::Int(id));
+                    out.push(bool_datum(run));
+                }
+                out
+            }
+            ServerCommand::NSet { id, controls } => {
+                let mut out = vec![Datum::Int(*id)];
+                out.extend(control_values(controls));
+                out
+            }
+            ServerCommand::NSetn { id, controls } => {
+                let mut out = vec![Datum::Int(*id)];
+                for &(ref control, ref values) in controls {
+                    out.push(control_datum(control));
+                    out.push(Datum::Int(values.len() as i32));
+                    out.extend(values.iter().map(|x| Datum::Float(*x)));
+                }
+                out
+            }
+            ServerCommand::NFill { id, controls } => {
+                let mut out = vec![Datum::Int(*id)];
+                for &(ref control, count, value) in controls {
+                    out.push(control_datum(control));
+                    out.push(Datum::Int(count));
+                    out.push(Datum::Float(value));
+                }
+                out
+            }
+            ServerCommand::NMap { id, controls } | ServerCommand::NMapa { id, controls } => {
+                let mut out = vec![Datum::Int(*id)];
+                out.extend(control_ints(controls));
+                out
+            }
+            ServerCommand::NMapn { id, controls } | ServerCommand::NMapan { id, controls } => {
+                let mut out = vec![Datum::Int(*id)];
+                out.extend(control_int_pairs(controls));
+                out
+            }
+            ServerCommand::NBefore { pairs } | ServerCommand::NAfter { pairs } => int_pairs(pairs),
+            ServerCommand::NQuery { ids } | ServerCommand::NTrace { ids } => ints(ids),
+            ServerCommand::NOrder { add_action, target, ids } => {
+                let mut out = vec![Datum::Int(*add_action as i32), Datum::Int(*target)];
+                out.extend(ints(ids));
+                out
+            }
+
+            ServerCommand::SNew { def_name, id, add_action, target, controls } => {
+                let mut out = vec![
+                    Datum::Str(def_name.clone()),
+                    Datum::Int(*id),
+                    Datum::Int(*add_action as i32),
+                    Datum::Int(*target),
+                ];
+                out.extend(control_values(controls));
+                out
+            }
+            ServerCommand::SGet { id, controls } => {
+                let mut out = vec![Datum::Int(*id)];
+                out.extend(controls.iter().map(|x| control_datum(x)));
+                out
+            }
+            ServerCommand::SGetn { id, controls } => {
+                let mut out = vec![Datum::Int(*id)];
+                out.extend(control_ints(controls));
+                out
+            }
+            ServerCommand::SNoid { ids } => ints(ids),
+
+            ServerCommand::GNew { groups } | ServerCommand::PNew { groups } => group_placements(groups),
+            ServerCommand::GHead { pairs } | ServerCommand::GTail { pairs } => int_pairs(pairs),
+            ServerCommand::GFreeAll { ids } | ServerCommand::GDeepFree { ids } => ints(ids),
+            ServerCommand::GDumpTree { groups } | ServerCommand::GQueryTree { groups } => group_flags(groups),
+
+            ServerCommand::UCmd { node, ugen_index, name, args } => {
+                let mut out = vec![Datum::Int(*node), Datum::Int(*ugen_index), Datum::Str(name.clone())];
+                out.extend(args.clone());
+                out
+            }
+
+            ServerCommand::BAlloc { bufnum, frames, channels } => {
+                vec![Datum::Int(*bufnum), Datum::Int(*frames), Datum::Int(*channels)]
+            }
+            ServerCommand::BAllocRead { bufnum, path, start, frames } => {
+                vec![Datum::Int(*bufnum), Datum::Str(path.clone()), Datum::Int(*start), Datum::Int(*frames)]
+            }
+            ServerCommand::BAllocReadChannel { bufnum, path, start, frames, channels } => {
+                let mut out = vec![Datum::Int(*bufnum), Datum::Str(path.clone()), Datum::Int(*start),
+                                   Datum::Int(*frames)];
+                out.extend(ints(channels));
+                out
+            }
+            ServerCommand::BRead { bufnum, path, file_start, frames, buf_start, leave_open } => {
+                vec![Datum::Int(*bufnum), Datum::Str(path.clone()), Datum::Int(*file_start),
+                     Datum::Int(*frames), Datum::Int(*buf_start), bool_datum(*leave_open)]
+            }
+            ServerCommand::BReadChannel { bufnum, path, file_start, frames, buf_start, leave_open, channels } => {
+                let mut out = vec![Datum::Int(*bufnum), Datum::Str(path.clone()), Datum::Int(*file_start),
+                                   Datum::Int(*frames), Datum::Int(*buf_start), bool_datum(*leave_open)];
+                out.extend(ints(channels));
+                out
+            }
+            ServerCommand::BWrite { bufnum, path, header_format, sample_format, frames, start, leave_open } => {
+                vec![Datum::Int(*bufnum), Datum::Str(path.clone()), Datum::Str(header_format.clone()),
+                     Datum::Str(sample_format.clone()), Datum::Int(*frames), Datum::Int(*start),
+                     bool_datum(*leave_open)]
+            }
+            ServerCommand::BFree { bufnum } | ServerCommand::BZero { bufnum } |
+            ServerCommand::BClose { bufnum } => vec![Datum::Int(*bufnum)],
+            ServerCommand::BSet { bufnum, samples } => {
+                let mut out = vec![Datum::Int(*bufnum)];
+                out.extend(index_values(samples));
+                out
+            }
+            ServerCommand::BSetn { bufnum, ranges } => {
+                let mut out = vec![Datum::Int(*bufnum)];
+                out.extend(index_ranges(ranges));
+                out
+            }
+            ServerCommand::BFill { bufnum, ranges } => {
+                let mut out = vec![Datum::Int(*bufnum)];
+                out.extend(index_fills(ranges));
+                out
+            }
+            ServerCommand::BGen { bufnum, name, args } => {
+                let mut out = vec![Datum::Int(*bufnum), Datum::Str(name.clone())];
+                out.extend(args.clone());
+                out
+            }
+            ServerCommand::BQuery { bufnums } => ints(bufnums),
+            ServerCommand::BGet { bufnum, indices } => {
+                let mut out = vec![Datum::Int(*bufnum)];
+                out.extend(ints(indices));
+                out
+            }
+            ServerCommand::BGetn { bufnum, ranges } => {
+                let mut out = vec![Datum::Int(*bufnum)];
+                out.extend(int_pairs(ranges));
+                out
+            }
+
+            ServerCommand::CSet { buses } => index_values(buses),
+            ServerCommand::CSetn { ranges } => index_ranges(ranges),
+            ServerCommand::CFill { ranges } => index_fills(ranges),
+            ServerCommand::CGet { buses } => ints(buses),
+            ServerCommand::CGetn { ranges } => int_pairs(ranges),
+        }
+    }
+
+    pub fn to_message(&self) -> Message {
+        Message::new(self.address(), self.arguments())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        encode_message(&self.to_message())
+    }
+
+    //Asynchronous commands scsynth can run a completion message or bundle after
+    pub fn takes_completion(&self) -> bool {
+        match self {
+            ServerCommand::DRecv { .. } | ServerCommand::DLoad { .. } | ServerCommand::DLoadDir { .. } |
+            ServerCommand::BAlloc { .. } | ServerCommand::BAllocRead { .. } |
+            ServerCommand::BAllocReadChannel { .. } | ServerCommand::BRead { .. } |
+            ServerCommand::BReadChannel { .. } | ServerCommand::BWrite { .. } |
+            ServerCommand::BFree { .. } | ServerCommand::BZero { .. } | ServerCommand::BClose { .. } => true,
+            _ => false,
+        }
+    }
+
+    //the command with the encoded completion packet as its last argument
+    pub fn to_message_with(&self, completion: &Packet) -> Message {
+        let mut message = self.to_message();
+        message.l_datum.push(Datum::Blob(encode_packet(completion)));
+        message
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct StatusInfo {
+    pub ugens: i32,
+    pub synths: i32,
+    pub groups: i32,
+    pub synthdefs: i32,
+    pub avg_cpu: f32,
+    pub peak_cpu: f32,
+    pub nominal_sample_rate: f64,
+    pub actual_sample_rate: f64,
+}
+
+//Position of a node in the tree, as carried by /n_go, /n_end, /n_info etc.
+#[derive(Clone, PartialEq, Debug)]
+pub struct NodeInfo {
+    pub id: i32,
+    pub parent: i32,
+    pub prev: i32,
+    pub next: i32,
+    //head and tail node ids, only present for groups
+    pub group: Option<(i32, i32)>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct BufferInfo {
+    pub bufnum: i32,
+    pub frames: i32,
+    pub channels: i32,
+    pub sample_rate: f32,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct VersionInfo {
+    pub program: String,
+    pub major: i32,
+    pub minor: i32,
+    pub patch: String,
+    pub branch: String,
+    pub commit: String,
+}
+
+//A node from /g_queryTree.reply
+#[derive(Clone, PartialEq, Debug)]
+pub enum QueryNode {
+    Group { id: i32, children: Vec<QueryNode> },
+    //values are floats, or strings like "c1" and "a2" for controls mapped to a bus
+    Synth { id: i32, def_name: String, controls: Vec<(ControlId, Datum)> },
+}
+
+fn write_query_node(f: &mut fmt::Formatter, node: &QueryNode, depth: usize) -> fmt::Result {
+    let indent = "   ".repeat(depth);
+    match node {
+        QueryNode::Group { id, children } => {
+            writeln!(f, "{}{} group", indent, id)?;
+            for child in children {
+                write_query_node(f, child, depth + 1)?;
+            }
+        }
+        QueryNode::Synth { id, def_name, controls } => {
+            writeln!(f, "{}{} {}", indent, id, def_name)?;
+            if !controls.is_empty() {
+                let mut line = Vec::new();
+                for &(ref control, ref value) in controls {
+                    let name = match control {
+                        ControlId::Index(index) => index.to_string(),
+                        ControlId::Name(name) => name.clone(),
+                    };
+                    let value = match value {
+                        Datum::Float(float) => float.to_string(),
+                        Datum::Str(strng) | Datum::Symbol(strng) => strng.clone(),
+                        other => format!("{:?}", other),
+                    };
+                    line.push(format!("{}: {}", name, value));
+                }
+                writeln!(f, "{}  {}", indent, line.join(" "))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+//prints the tree the way sclang's queryAllNodes does
+impl fmt::Display for QueryNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryNode::Group { id, children } => {
+                writeln!(f, "NODE TREE Group {}", id)?;
+                for child in children {
+                    write_query_node(f, child, 1)?;
+                }
+                Ok(())
+            }
+            synth => write_query_node(f, synth, 0),
+        }
+    }
+}
+
+//Replies and notifications sent by scsynth
+#[derive(Clone, PartialEq, Debug)]
+pub enum ServerReply {
+    Done { command: String, args: Vec<Datum> },
+    Fail { command: String, error: String, args: Vec<Datum> },
+    Status(StatusInfo),
+    Synced { id: i32 },
+    NodeGo(NodeInfo),
+    NodeEnd(NodeInfo),
+    NodeOff(NodeInfo),
+    NodeOn(NodeInfo),
+    NodeMove(NodeInfo),
+    NodeInfo(NodeInfo),
+    NSet { id: i32, controls: Vec<(ControlId, f32)> },
+    NSetn { id: i32, controls: Vec<(ControlId, Vec<f32>)> },
+    Trigger { node: i32, id: i32, value: f32 },
+    BufferInfo(Vec<BufferInfo>),
+    BSet { bufnum: i32, samples: Vec<(i32, f32)> },
+    BSetn { bufnum: i32, ranges: Vec<(i32, Vec<f32>)> },
+    CSet { buses: Vec<(i32, f32)> },
+    CSetn { ranges: Vec<(i32, Vec<f32>)> },
+    QueryTree(QueryNode),
+    Version(VersionInfo),
+    //anything else, replies to /cmd and SendReply messages included
+    Other(Message),
+}
+
+//Reads reply arguments, accepting any numeric type where a number is expected
+struct Args<'a> {
+    name: &'a str,
+    l_datum: &'a [Datum],
+    pos: usize,
+}
+
+impl<'a> Args<'a> {
+    fn new(message: &'a Message) -> Args<'a> {
+        Args {
+            name: &message.name,
+            l_datum: &message.l_datum,
+            pos: 0,
+        }
+    }
+
+    fn error(&self) -> OscError {
+        OscError::UnexpectedArguments(self.name.to_string())
+    }
+
+    fn next(&mut self) -> Result<&'a Datum, OscError> {
+        match self.l_datum.get(self.pos) {
+            Some(datum) => {
+                self.pos += 1;
+                Ok(datum)
+            }
+            None => Err(self.error()),
+        }
+    }
+
+    fn int(&mut self) -> Result<i32, OscError> {
+        match self.next()? {
+            Datum::Int(int) => Ok(*int),
+            Datum::Int64(int) => Ok(*int as i32),
+            Datum::Float(float) => Ok(*float as i32),
+            Datum::Double(double) => Ok(*double as i32),
+            _ => Err(self.error()),
+        }
+    }
+
+    fn float(&mut self) -> Result<f32, OscError> {
+        Ok(self.double()? as f32)
+    }
+
+    fn double(&mut self) -> Result<f64, OscError> {
+        match self.next()? {
+            Datum::Int(int) => Ok(*int as f64),
+            Datum::Int64(int) => Ok(*int as f64),
+            Datum::Float(float) => Ok(*float as f64),
+            Datum::Double(double) => Ok(*double),
+            _ => Err(self.error()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, OscError> {
+        match self.next()? {
+            Datum::Str(strng) | Datum::Symbol(strng) => Ok(strng.clone()),
+            _ => Err(self.error()),
+        }
+    }
+
+    fn control(&mut self) -> Result<ControlId, OscError> {
+        match self.next()? {
+            Datum::Str(strng) | Datum::Symbol(strng) => Ok(ControlId::Name(strng.clone())),
+            Datum::Int(int) => Ok(ControlId::Index(*int)),
+            _ => Err(self.error()),
+        }
+    }
+
+    fn floats(&mut self, count: i32) -> Result<Vec<f32>, OscError> {
+        let mut out = Vec::new();
+        for _ in 0..count {
+            out.push(self.float()?);
+        }
+        Ok(out)
+    }
+
+    fn rest(&mut self) -> Vec<Datum> {
+        let out = self.l_datum[self.pos..].to_vec();
+        self.pos = self.l_datum.len();
+        out
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.l_datum.len()
+    }
+
+    fn node_info(&mut self) -> Result<NodeInfo, OscError> {
+        let id = self.int()?;
+        let parent = self.int()?;
+        let prev = self.int()?;
+        let next = self.int()?;
+        let group = if self.int()? == 1 {
+            Some((self.int()?, self.int()?))
+        } else {
+            None
+        };
+        Ok(NodeInfo {
+            id: id,
+            parent: parent,
+            prev: prev,
+            next: next,
+            group: group,
+        })
+    }
+
+    //nodes come depth first, a child count of -1 marks a synth
+    fn query_node(&mut self, controls: bool) -> Result<QueryNode, OscError> {
+        let id = self.int()?;
+        let count = self.int()?;
+        if count < 0 {
+            let def_name = self.string()?;
+            let mut values = Vec::new();
+            if controls {
+                for _ in 0..self.int()? {
+                    values.push((self.control()?, self.next()?.clone()));
+                }
+            }
+            return Ok(QueryNode::Synth {
+                id: id,
+                def_name: def_name,
+                controls: values,
+            });
+        }
+        let mut children = Vec::new();
+        for _ in 0..count {
+            children.push(self.query_node(controls)?);
+        }
+        Ok(QueryNode::Group {
+            id: id,
+            children: children,
+        })
+    }
+
+    fn index_values(&mut self) -> Result<Vec<(i32, f32)>, OscError> {
+        let mut out = Vec::new();
+        while !self.is_empty() {
+            out.push((self.int()?, self.float()?));
+        }
+        Ok(out)
+    }
+
+    fn index_ranges(&mut self) -> Result<Vec<(i32, Vec<f32>)>, OscError> {
+        let mut out = Vec::new();
+        while !self.is_empty() {
+            let start = self.int()?;
+            let count = self.int()?;
+            out.push((start, self.floats(count)?));
+        }
+        Ok(out)
+    }
+}
+
+impl ServerReply {
+    pub fn from_message(message: &Message) -> Result<ServerReply, OscError> {
+        let mut args = Args::new(message);
+        let reply = match message.name.as_str() {
+            "/done" => ServerReply::Done {
+                command: args.string()?,
+                args: args.rest(),
+            },
+            "/fail" => ServerReply::Fail {
+                command: args.string()?,
+                error: args.string()?,
+                args: args.rest(),
+            },
+            "/status.reply" => {
+                args.int()?;
+                ServerReply::Status(StatusInfo {
+                    ugens: args.int()?,
+                    synths: args.int()?,
+                    groups: args.int()?,
+                    synthdefs: args.int()?,
+                    avg_cpu: args.float()?,
+                    peak_cpu: args.float()?,
+                    nominal_sample_rate: args.double()?,
+                    actual_sample_rate: args.double()?,
+                })
+            }
+            "/synced" => ServerReply::Synced { id: args.int()? },
+            "/n_go" => ServerReply::NodeGo(args.node_info()?),
+            "/n_end" => ServerReply::NodeEnd(args.node_info()?),
+            "/n_off" => ServerReply::NodeOff(args.node_info()?),
+            "/n_on" => ServerReply::NodeOn(args.node_info()?),
+            "/n_move" => ServerReply::NodeMove(args.node_info()?),
+            "/n_info" => ServerReply::NodeInfo(args.node_info()?),
+            "/n_set" => {
+                let id = args.int()?;
+                let mut controls = Vec::new();
+                while !args.is_empty() {
+                    controls.push((args.control()?, args.float()?));
+                }
+                ServerReply::NSet { id: id, controls: controls }
+            }
+            "/n_setn" => {
+                let id = args.int()?;
+                let mut controls = Vec::new();
+                while !args.is_empty() {
+                    let control = args.control()?;
+                    let count = args.int()?;
+                    controls.push((control, args.floats(count)?));
+                }
+                ServerReply::NSetn { id: id, controls: controls }
+            }
+            "/tr" => ServerReply::Trigger {
+                node: args.int()?,
+                id: args.int()?,
+                value: args.float()?,
+            },
+            "/b_info" => {
+                let mut infos = Vec::new();
+                while !args.is_empty() {
+                    infos.push(BufferInfo {
+                        bufnum: args.int()?,
+                        frames: args.int()?,
+                        channels: args.int()?,
+                        sample_rate: args.float()?,
+                    });
+                }
+                ServerReply::BufferInfo(infos)
+            }
+            "/b_set" => ServerReply::BSet {
+                bufnum: args.int()?,
+                samples: args.index_values()?,
+            },
+            "/b_setn" => ServerReply::BSetn {
+                bufnum: args.int()?,
+                ranges: args.index_ranges()?,
+            },
+            "/c_set" => ServerReply::CSet { buses: args.index_values()? },
+            "/c_setn" => ServerReply::CSetn { ranges: args.index_ranges()? },
+            "/g_queryTree.reply" => {
+                let controls = args.int()? != 0;
+                ServerReply::QueryTree(args.query_node(controls)?)
+            }
+            "/version.reply" => ServerReply::Version(VersionInfo {
+                program: args.string()?,
+                major: args.int()?,
+                minor: args.int()?,
+                patch: args.string()?,
+                branch: args.string()?,
+                commit: args.string()?,
+            }),
+            _ => ServerReply::Other(message.clone()),
+        };
+        Ok(reply)
+    }
+}
+
+#[test]
+fn test_server_command() {
+    let s_new = ServerCommand::SNew {
+        def_name: "sine".to_string(),
+        id: 1000,
+        add_action: AddAction::AddToTail,
+        target: 1,
+        controls: vec![("freq".into(), 220.0), (1.into(), 0.5)],
+    };
+    assert_eq!(s_new.to_message(), Message::new("/s_new", vec![
+        Datum::Str("sine".to_string()), Datum::Int(1000), Datum::Int(1), Datum::Int(1),
+        Datum::Str("freq".to_string()), Datum::Float(220.0), Datum::Int(1), Datum::Float(0.5),
+    ]));
+    let setn = ServerCommand::CSetn { ranges: vec![(4, vec![0.1, 0.2])] };
+    assert_eq!(setn.to_message().l_datum, vec![Datum::Int(4), Datum::Int(2), Datum::Float(0.1), Datum::Float(0.2)]);
+    assert_eq!(decode_message(&ServerCommand::Notify { on: true, client_id: None }.encode()),
+               Ok(Message::new("/notify", vec![Datum::Int(1)])));
+    assert!(ServerCommand::DRecv { data: vec![] }.is_async());
+    assert!(!ServerCommand::NFree { ids: vec![1000] }.is_async());
+    let d_recv = ServerCommand::DRecv { data: vec![1, 2] }.to_message_with(&Packet::Message(s_new.to_message()));
+    assert_eq!(d_recv.l_datum, vec![Datum::Blob(vec![1, 2]), Datum::Blob(s_new.encode())]);
+    assert!(!ServerCommand::BGen { bufnum: 0, name: "sine1".to_string(), args: vec![] }.takes_completion());
+}
+
+#[test]
+fn test_server_reply() {
+    let status = Message::new("/status.reply", vec![
+        Datum::Int(1), Datum::Int(12), Datum::Int(2), Datum::Int(3), Datum::Int(40),
+        Datum::Float(1.5), Datum::Float(3.0), Datum::Double(48000.0), Datum::Double(47999.9),
+    ]);
+    match ServerReply::from_message(&status) {
+        Ok(ServerReply::Status(info)) => {
+            assert_eq!(info.synths, 2);
+            assert_eq!(info.synthdefs, 40);
+            assert_eq!(info.nominal_sample_rate, 48000.0);
+        }
+        other => panic!("status.reply {:?}", other),
+    }
+    let n_go = Message::new("/n_go", vec![
+        Datum::Int(2), Datum::Int(1), Datum::Int(-1), Datum::Int(1000), Datum::Int(1), Datum::Int(-1), Datum::Int(-1),
+    ]);
+    assert_eq!(ServerReply::from_message(&n_go), Ok(ServerReply::NodeGo(NodeInfo {
+        id: 2, parent: 1, prev: -1, next: 1000, group: Some((-1, -1)),
+    })));
+    let setn = Message::new("/n_setn", vec![
+        Datum::Int(1000), Datum::Str("freqs".to_string()), Datum::Int(2), Datum::Float(1.0), Datum::Float(2.0),
+    ]);
+    assert_eq!(ServerReply::from_message(&setn), Ok(ServerReply::NSetn {
+        id: 1000, controls: vec![("freqs".into(), vec![1.0, 2.0])],
+    }));
+    let short = Message::new("/tr", vec![Datum::Int(1000), Datum::Int(1)]);
+    assert_eq!(ServerReply::from_message(&short), Err(OscError::UnexpectedArguments("/tr".to_string())));
+    let reply = Message::new("/amp", vec![Datum::Int(1000)]);
+    assert_eq!(ServerReply::from_message(&reply), Ok(ServerReply::Other(reply.clone())));
+}
+
+#[test]
+fn test_query_tree() {
+    let reply = Message::new("/g_queryTree.reply", vec![
+        Datum::Int(1), Datum::Int(0), Datum::Int(1),
+        Datum::Int(1), Datum::Int(2),
+        Datum::Int(1000), Datum::Int(-1), Datum::Str("sine".to_string()), Datum::Int(2),
+        Datum::Str("freq".to_string()), Datum::Float(440.0), Datum::Str("amp".to_string()), Datum::Str("c3".to_string()),
+        Datum::Int(2), Datum::Int(0),
+    ]);
+    let tree = match ServerReply::from_message(&reply) {
+        Ok(ServerReply::QueryTree(tree)) => tree,
+        other => panic!("g_queryTree.reply {:?}", other),
+    };
+    assert_eq!(tree.to_string(),
+               "NODE TREE Group 0\n   1 group\n      1000 sine\n        freq: 440 amp: c3\n      2 group\n");
+}
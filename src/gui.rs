@@ -2,12 +2,13 @@
 use iui::prelude::*;
 use iui::controls::{Label, Button, VerticalBox, Group};
 
-use osc::*;
+use server::*;
 use ugens::*;
 
 pub fn run_gui() {
        // Initialize the UI library
     let ui = UI::init().expect("Couldn't initialize UI library");
+    let server = Server::new(ServerOptions::default()).expect("Couldn't bind the server socket");
     // Create a window into which controls can be placed
     let mut win = Window::new(&ui, "Test App", 200, 200, WindowType::NoMenubar);
     
@@ -22,34 +23,42 @@ pub fn run_gui() {
     let mut button = Button::new(&ui, "Start");
     button.on_clicked(&ui, {
         let ui = ui.clone();
+        let server = server.clone();
         move |btn| {
             btn.set_text(&ui, "Started!");
-            sc_start();
+            if let Err(err) = sc_start(&server) {
+                println!("sc_start: {}", err);
+            }
         }
     });
     let mut button1 = Button::new(&ui, "Play");
     button1.on_clicked(&ui, {
         let ui = ui.clone();
+        let server = server.clone();
         move |btn| {
             btn.set_text(&ui, "Playing");
-            play_demo_1();
+            play_demo_1(&server);
         }
     });
     let mut button11 = Button::new(&ui, "Play Stereo(?)");
     button11.on_clicked(&ui, {
         let ui = ui.clone();
+        let server = server.clone();
         move |btn| {
             btn.set_text(&ui, "Playing");
-            play_demo_2();
+            play_demo_2(&server);
         }
     });
 
     let mut button2 = Button::new(&ui, "Stop");
     button2.on_clicked(&ui, {
         let ui = ui.clone();
+        let server = server.clone();
         move |btn| {
             btn.set_text(&ui, "Stopped!");
-            sc_stop();
+            if let Err(err) = sc_stop(&server) {
+                println!("sc_stop: {}", err);
+            }
         }
     });
 
@@ -1,7 +1,14 @@
 mod osc;
+mod alloc;
+mod commands;
 #[macro_use]
 mod ugens;
 mod sc3;
+mod server;
+mod node;
+mod buffer;
+mod signal;
+mod bus;
 mod gui;
 mod utils;
 //#![allow(dead_code)]
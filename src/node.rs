@@ -0,0 +1,414 @@
+use bus::Bus;
+use commands::*;
+use sc3::Rate;
+use server::{Server, ServerError};
+use std::collections::HashMap;
+
+//A synth running on the server. The handle only holds the node id, so the node
+//keeps playing when it is dropped unless free_on_drop was asked for. Once the
+//node has ended its id can go to another node, so commands through the handle
+//fail with NodeEnded and dropping it sends nothing.
+pub struct Synth {
+    server: Server,
+    id: i32,
+    def_name: String,
+    free_on_drop: bool,
+    //node time when the handle was made, see Server::node_ended
+    since: u64,
+}
+
+impl Synth {
+    //sends /s_new for an already loaded def, at the tail of the default group
+    pub fn new(server: &Server, def_name: &str, controls: Vec<(ControlId, f32)>) -> Result<Synth, ServerError> {
+        Synth::new_at(server, def_name, controls, AddAction::AddToTail, server.default_group())
+    }
+
+    pub fn new_at(server: &Server, def_name: &str, controls: Vec<(ControlId, f32)>,
+                  add_action: AddAction, target: i32) -> Result<Synth, ServerError> {
+        let synth = Synth::from_id(server, def_name, server.next_node_id());
+        synth.send(&synth.s_new(controls, add_action, target))?;
+        Ok(synth)
+    }
+
+    //wraps a node id the caller already created, for example inside a bundle
+    pub fn from_id(server: &Server, def_name: &str, id: i32) -> Synth {
+        Synth {
+            server: server.clone(),
+            id: id,
+            def_name: def_name.to_string(),
+            free_on_drop: false,
+            since: server.node_time(),
+        }
+    }
+
+    pub fn s_new(&self, controls: Vec<(ControlId, f32)>, add_action: AddAction, target: i32) -> ServerCommand {
+        ServerCommand::SNew {
+            def_name: self.def_name.clone(),
+            id: self.id,
+            add_action: add_action,
+            target: target,
+            controls: controls,
+        }
+    }
+
+    pub fn free_on_drop(mut self, free: bool) -> Synth {
+        self.free_on_drop = free;
+        self
+    }
+
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn def_name(&self) -> &str {
+        &self.def_name
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    //true once the server reported the end of this node, freed or done by itself
+    pub fn ended(&self) -> bool {
+        self.server.node_ended(self.id, self.since)
+    }
+
+    fn send(&self, command: &ServerCommand) -> Result<(), ServerError> {
+        if self.ended() {
+            return Err(ServerError::NodeEnded(self.id));
+        }
+        self.server.send_command(command)
+    }
+
+    pub fn set<C: Into<ControlId>>(&self, control: C, value: f32) -> Result<(), ServerError> {
+        self.send(&ServerCommand::NSet {
+            id: self.id,
+            controls: vec![(control.into(), value)],
+        })
+    }
+
+    pub fn setn<C: Into<ControlId>>(&self, control: C, values: Vec<f32>) -> Result<(), ServerError> {
+        self.send(&ServerCommand::NSetn {
+            id: self.id,
+            controls: vec![(control.into(), values)],
+        })
+    }
+
+    //reads the control from a control bus; a bus of -1 unmaps it
+    pub fn map<C: Into<ControlId>>(&self, control: C, bus: i32) -> Result<(), ServerError> {
+        self.send(&ServerCommand::NMap {
+            id: self.id,
+            controls: vec![(control.into(), bus)],
+        })
+    }
+
+    //reads the control from an audio bus; a bus of -1 unmaps it
+    pub fn map_audio<C: Into<ControlId>>(&self, control: C, bus: i32) -> Result<(), ServerError> {
+        self.send(&ServerCommand::NMapa {
+            id: self.id,
+            controls: vec![(control.into(), bus)],
+        })
+    }
+
+    //maps the control and the ones after it to each channel of the bus
+    pub fn map_bus<C: Into<ControlId>>(&self, control: C, bus: &Bus) -> Result<(), ServerError> {
+        let controls = vec![(control.into(), bus.index(), bus.channels())];
+        let command = match bus.rate() {
+            Rate::RateAr => ServerCommand::NMapan { id: self.id, controls: controls },
+            _ => ServerCommand::NMapn { id: self.id, controls: controls },
+        };
+        self.send(&command)
+    }
+
+    pub fn run(&self, run: bool) -> Result<(), ServerError> {
+        self.send(&ServerCommand::NRun { nodes: vec![(self.id, run)] })
+    }
+
+    pub fn pause(&self) -> Result<(), ServerError> {
+        self.run(false)
+    }
+
+    //Closes the gate control. A positive time overrides the envelope's release
+    //time the way sclang does it, by setting gate to -1 - time.
+    pub fn release(&self, time: f32) -> Result<(), ServerError> {
+        let gate = if time > 0.0 { -1.0 - time } else { 0.0 };
+        self.set("gate", gate)
+    }
+
+    pub fn move_before(&self, target: i32) -> Result<(), ServerError> {
+        self.send(&ServerCommand::NBefore { pairs: vec![(self.id, target)] })
+    }
+
+    pub fn move_after(&self, target: i32) -> Result<(), ServerError> {
+        self.send(&ServerCommand::NAfter { pairs: vec![(self.id, target)] })
+    }
+
+    pub fn free(mut self) -> Result<(), ServerError> {
+        self.free_on_drop = false;
+        self.send(&ServerCommand::NFree { ids: vec![self.id] })
+    }
+}
+
+impl Drop for Synth {
+    fn drop(&mut self) {
+        if self.free_on_drop && !self.ended() {
+            let _ = self.server.send_command(&ServerCommand::NFree { ids: vec![self.id] });
+        }
+    }
+}
+
+//A group on the server. Like Synth, dropping the handle leaves the group alone.
+pub struct Group {
+    server: Server,
+    id: i32,
+}
+
+impl Group {
+    pub fn new(server: &Server, add_action: AddAction, target: i32) -> Result<Group, ServerError> {
+        let group = Group::from_id(server, server.next_node_id());
+        server.send_command(&ServerCommand::GNew { groups: vec![(group.id, add_action, target)] })?;
+        Ok(group)
+    }
+
+    pub fn head(server: &Server, target: i32) -> Result<Group, ServerError> {
+        Group::new(server, AddAction::AddToHead, target)
+    }
+
+    pub fn tail(server: &Server, target: i32) -> Result<Group, ServerError> {
+        Group::new(server, AddAction::AddToTail, target)
+    }
+
+    pub fn before(server: &Server, target: i32) -> Result<Group, ServerError> {
+        Group::new(server, AddAction::AddBefore, target)
+    }
+
+    pub fn after(server: &Server, target: i32) -> Result<Group, ServerError> {
+        Group::new(server, AddAction::AddAfter, target)
+    }
+
+    pub fn replace(server: &Server, target: i32) -> Result<Group, ServerError> {
+        Group::new(server, AddAction::AddReplace, target)
+    }
+
+    pub fn from_id(server: &Server, id: i32) -> Group {
+        Group {
+            server: server.clone(),
+            id: id,
+        }
+    }
+
+    //the group sc_start creates for this client
+    pub fn default_group(server: &Server) -> Group {
+        Group::from_id(server, server.default_group())
+    }
+
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    pub fn run(&self, run: bool) -> Result<(), ServerError> {
+        self.server.send_command(&ServerCommand::NRun { nodes: vec![(self.id, run)] })
+    }
+
+    //sets a control on every synth in the group
+    pub fn set<C: Into<ControlId>>(&self, control: C, value: f32) -> Result<(), ServerError> {
+        self.server.send_command(&ServerCommand::NSet {
+            id: self.id,
+            controls: vec![(control.into(), value)],
+        })
+    }
+
+    pub fn move_before(&self, target: i32) -> Result<(), ServerError> {
+        self.server.send_command(&ServerCommand::NBefore { pairs: vec![(self.id, target)] })
+    }
+
+    pub fn move_after(&self, target: i32) -> Result<(), ServerError> {
+        self.server.send_command(&ServerCommand::NAfter { pairs: vec![(self.id, target)] })
+    }
+
+    pub fn move_node_to_head(&self, node: i32) -> Result<(), ServerError> {
+        self.server.send_command(&ServerCommand::GHead { pairs: vec![(self.id, node)] })
+    }
+
+    pub fn move_node_to_tail(&self, node: i32) -> Result<(), ServerError> {
+        self.server.send_command(&ServerCommand::GTail { pairs: vec![(self.id, node)] })
+    }
+
+    //frees everything in the group, nested groups included
+    pub fn free_all(&self) -> Result<(), ServerError> {
+        self.server.send_command(&ServerCommand::GFreeAll { ids: vec![self.id] })
+    }
+
+    //frees every synth below the group, keeping the nested groups
+    pub fn deep_free(&self) -> Result<(), ServerError> {
+        self.server.send_command(&ServerCommand::GDeepFree { ids: vec![self.id] })
+    }
+
+    pub fn free(self) -> Result<(), ServerError> {
+        self.server.send_command(&ServerCommand::NFree { ids: vec![self.id] })
+    }
+}
+
+#[derive(Clone, Debug)]
+struct TreeNode {
+    parent: i32,
+    //None for synths
+    children: Option<Vec<i32>>,
+}
+
+//Client side copy of the server's node tree, kept up to date from /n_go, /n_end
+//and /n_move. It only sees nodes created while notifications are on.
+#[derive(Clone, Debug)]
+pub struct NodeTree {
+    nodes: HashMap<i32, TreeNode>,
+}
+
+impl NodeTree {
+    pub fn new() -> NodeTree {
+        let mut nodes = HashMap::new();
+        nodes.insert(0, TreeNode { parent: -1, children: Some(Vec::new()) });
+        NodeTree { nodes: nodes }
+    }
+
+    pub fn contains(&self, id: i32) -> bool {
+        self.nodes.contains_key(&id)
+    }
+
+    pub fn is_group(&self, id: i32) -> bool {
+        self.nodes.get(&id).map_or(false, |node| node.children.is_some())
+    }
+
+    pub fn parent(&self, id: i32) -> Option<i32> {
+        self.nodes.get(&id).map(|node| node.parent).filter(|&parent| parent >= 0)
+    }
+
+    //children of a group from head to tail
+    pub fn children(&self, id: i32) -> Vec<i32> {
+        match self.nodes.get(&id) {
+            Some(&TreeNode { children: Some(ref children), .. }) => children.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, reply: &ServerReply) {
+        match reply {
+            ServerReply::NodeGo(info) => self.insert(info),
+            ServerReply::NodeMove(info) => {
+                self.unlink(info.id);
+                self.insert(info);
+            }
+            ServerReply::NodeEnd(info) => self.remove(info.id),
+            _ => {}
+        }
+    }
+
+    fn insert(&mut self, info: &NodeInfo) {
+        let children = match self.nodes.get(&info.id) {
+            Some(node) => node.children.clone(),
+            None => info.group.map(|_| Vec::new()),
+        };
+        self.nodes.insert(info.id, TreeNode { parent: info.parent, children: children });
+        if let Some(&mut TreeNode { children: Some(ref mut siblings), .. }) = self.nodes.get_mut(&info.parent) {
+            let pos = match siblings.iter().position(|&x| x == info.prev) {
+                Some(pos) => pos + 1,
+                None => siblings.iter().position(|&x| x == info.next).unwrap_or(
+                    if info.prev == -1 { 0 } else { siblings.len() }),
+            };
+            siblings.insert(pos, info.id);
+        }
+    }
+
+    fn unlink(&mut self, id: i32) {
+        let parent = match self.nodes.get(&id) {
+            Some(node) => node.parent,
+            None => return,
+        };
+        if let Some(&mut TreeNode { children: Some(ref mut siblings), .. }) = self.nodes.get_mut(&parent) {
+            siblings.retain(|&x| x != id);
+        }
+    }
+
+    fn remove(&mut self, id: i32) {
+        self.unlink(id);
+        if let Some(node) = self.nodes.remove(&id) {
+            for child in node.children.unwrap_or(Vec::new()) {
+                self.remove(child);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_synth_messages() {
+    use osc::*;
+    use server::{fake_options, FakeScsynth};
+    let fake = FakeScsynth::new();
+    let server = Server::new(fake_options(&fake)).unwrap();
+    {
+        let synth = Synth::new(&server, "sine", vec![("freq".into(), 440.0)]).unwrap().free_on_drop(true);
+        let id = synth.id();
+        assert_eq!(fake.next_message().l_datum, vec![Datum::Str("sine".to_string()), Datum::Int(id), Datum::Int(1),
+                                                     Datum::Int(1), Datum::Str("freq".to_string()), Datum::Float(440.0)]);
+        synth.set("freq", 220.0).unwrap();
+        assert_eq!(fake.next_message().l_datum, vec![Datum::Int(id), Datum::Str("freq".to_string()), Datum::Float(220.0)]);
+        synth.release(2.0).unwrap();
+        assert_eq!(fake.next_message().l_datum, vec![Datum::Int(id), Datum::Str("gate".to_string()), Datum::Float(-3.0)]);
+    }
+    assert_eq!(fake.next_message().name, "/n_free");
+}
+
+#[test]
+fn test_node_tree() {
+    let info = |id, parent, prev, next, group| NodeInfo { id: id, parent: parent, prev: prev, next: next, group: group };
+    let mut tree = NodeTree::new();
+    tree.update(&ServerReply::NodeGo(info(1, 0, -1, -1, Some((-1, -1)))));
+    tree.update(&ServerReply::NodeGo(info(1000, 1, -1, -1, None)));
+    tree.update(&ServerReply::NodeGo(info(1001, 1, 1000, -1, None)));
+    tree.update(&ServerReply::NodeGo(info(1002, 1, -1, 1000, Some((-1, -1)))));
+    assert_eq!(tree.children(1), vec![1002, 1000, 1001]);
+    tree.update(&ServerReply::NodeGo(info(1003, 1002, -1, -1, None)));
+    tree.update(&ServerReply::NodeMove(info(1001, 1002, 1003, -1, None)));
+    assert_eq!(tree.children(1), vec![1002, 1000]);
+    assert_eq!(tree.children(1002), vec![1003, 1001]);
+    assert_eq!(tree.parent(1001), Some(1002));
+    tree.update(&ServerReply::NodeEnd(info(1002, 1, -1, 1000, Some((1003, 1001)))));
+    assert_eq!(tree.children(1), vec![1000]);
+    assert!(!tree.contains(1003));
+    assert!(tree.is_group(1));
+}
+
+#[test]
+fn test_ended_synth() {
+    use osc::*;
+    use server::{fake_options, FakeScsynth};
+    use std::thread;
+    use std::time::Duration;
+    let fake = FakeScsynth::new();
+    let server = Server::new(fake_options(&fake)).unwrap();
+    let synth = Synth::new(&server, "sine", vec![]).unwrap().free_on_drop(true);
+    let id = synth.id();
+    assert_eq!(fake.next_message().name, "/s_new");
+    //the synth ends by itself, as with doneAction 2
+    fake.send_to_client(&Message::new("/n_end", vec![Datum::Int(id), Datum::Int(1), Datum::Int(-1), Datum::Int(-1), Datum::Int(0)]));
+    for _ in 0..200 {
+        if synth.ended() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(synth.ended());
+    let other = Synth::from_id(&server, "sine", server.next_node_id());
+    assert_eq!(other.id(), id);
+    assert!(!other.ended());
+    match synth.set("freq", 220.0) {
+        Err(ServerError::NodeEnded(ended)) => assert_eq!(ended, id),
+        _ => panic!("command sent to an ended node"),
+    }
+    drop(synth);
+    other.run(false).unwrap();
+    assert_eq!(fake.next_message().name, "/n_run");
+}
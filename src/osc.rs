@@ -1,11 +1,12 @@
-use sc3::{synthdef, Ugen, Mce, Mrg};
-use utils::{print_bytes};
-use std::mem;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
 use std::net;
-use std::time::Duration;
-use ugens::*;
-use std::thread::*;
-use std::time::*;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub fn encode_i8(num: i32) -> Vec<u8> {
     let n = (num & 0xff) as u8;
@@ -42,13 +43,37 @@ pub fn decode_i16(buf: Vec<u8>) -> i32 {
 }
 
 pub fn decode_i32(buf: Vec<u8>) -> i32 {
-    let n1: u32 = (buf[0] as u32) << 8;
+    let n1: u32 = (buf[0] as u32) << 24;
     let n2: u32 = (buf[1] as u32) << 16;
-    let n3: u32 = (buf[2] as u32) << 24;
+    let n3: u32 = (buf[2] as u32) << 8;
     let num = buf[3] as u32 | n3 | n2 | n1;
     num as i32
 }
 
+pub fn decode_f32(buf: Vec<u8>) -> f32 {
+    f32::from_bits(decode_i32(buf) as u32)
+}
+
+pub fn encode_i64(num: i64) -> Vec<u8> {
+    let mut out = encode_i32((num >> 32) as i32);
+    out.extend(encode_i32(num as i32));
+    out
+}
+
+pub fn encode_f64(num: f64) -> Vec<u8> {
+    encode_i64(num.to_bits() as i64)
+}
+
+pub fn decode_i64(buf: Vec<u8>) -> i64 {
+    let hi = decode_i32(buf[0..4].to_vec()) as u32 as u64;
+    let lo = decode_i32(buf[4..8].to_vec()) as u32 as u64;
+    ((hi << 32) | lo) as i64
+}
+
+pub fn decode_f64(buf: Vec<u8>) -> f64 {
+    f64::from_bits(decode_i64(buf) as u64)
+}
+
 pub fn encode_str(str1: &String) -> Vec<u8> {
     let bb = str1.clone().into_bytes();
     bb
@@ -79,20 +104,36 @@ fn encode_string(str1: &String) -> Vec<u8> {
     extend_(0 as u8, &encode_str(str1))
 }
 
+//blobs, unlike strings, carry their size and are not null terminated
 fn encode_blob(bts: &Vec<u8>) -> Vec<u8> {
     let b1 = encode_i32(bts.len() as i32);
     let mut out = Vec::new();
     out.extend(b1);
-    out.extend(extend_(0 as u8, bts));
+    out.extend(bts);
+    while out.len() % 4 != 0 {
+        out.push(0);
+    }
     out
 }
 
 #[derive(Clone, PartialEq, Debug)]
-enum Datum {
+pub enum Datum {
     Int(i32),
     Float(f32),
     Str(String),
     Blob(Vec<u8>),
+    Int64(i64),
+    Double(f64),
+    TimeTag(u64),
+    Char(char),
+    Symbol(String),
+    Midi([u8; 4]),
+    Rgba(u32),
+    True,
+    False,
+    Nil,
+    Infinitum,
+    Array(Vec<Datum>),
 }
 
 fn encode_datum(datum: &Datum) -> Vec<u8> {
@@ -101,248 +142,1107 @@ fn encode_datum(datum: &Datum) -> Vec<u8> {
         Datum::Float(float) => encode_f32(*float),
         Datum::Str(strng) => encode_string(strng),
         Datum::Blob(blob) => encode_blob(blob),
+        Datum::Int64(int) => encode_i64(*int),
+        Datum::Double(double) => encode_f64(*double),
+        Datum::TimeTag(time) => encode_i64(*time as i64),
+        Datum::Char(chr) => encode_i32(*chr as i32),
+        Datum::Symbol(strng) => encode_string(strng),
+        Datum::Midi(midi) => midi.to_vec(),
+        Datum::Rgba(rgba) => encode_i32(*rgba as i32),
+        Datum::True | Datum::False | Datum::Nil | Datum::Infinitum => Vec::new(),
+        Datum::Array(data) => {
+            let mut out = Vec::new();
+            for elem in data {
+                out.extend(encode_datum(elem));
+            }
+            out
+        }
     }
 }
 
 fn tag(datum: &Datum) -> char {
     match datum {
-        Datum::Int(int) => 'i',
-        Datum::Float(float) => 'f',
-        Datum::Str(strng) => 's',
-        Datum::Blob(blob) => 'b',
+        Datum::Int(_) => 'i',
+        Datum::Float(_) => 'f',
+        Datum::Str(_) => 's',
+        Datum::Blob(_) => 'b',
+        Datum::Int64(_) => 'h',
+        Datum::Double(_) => 'd',
+        Datum::TimeTag(_) => 't',
+        Datum::Char(_) => 'c',
+        Datum::Symbol(_) => 'S',
+        Datum::Midi(_) => 'm',
+        Datum::Rgba(_) => 'r',
+        Datum::True => 'T',
+        Datum::False => 'F',
+        Datum::Nil => 'N',
+        Datum::Infinitum => 'I',
+        Datum::Array(_) => '[',
     }
 }
 
-fn descriptor(id: Vec<Datum>) -> String {
-    let mut outs: String = ",".to_string();
-    for dt in id {
-        outs.push(tag(&dt));
+fn push_tags(l_datum: &Vec<Datum>, outs: &mut String) {
+    for dt in l_datum {
+        match dt {
+            Datum::Array(data) => {
+                outs.push('[');
+                push_tags(data, outs);
+                outs.push(']');
+            }
+            _ => outs.push(tag(dt)),
+        }
     }
+}
+
+fn descriptor(id: &Vec<Datum>) -> String {
+    let mut outs: String = ",".to_string();
+    push_tags(id, &mut outs);
     outs
 }
 
-struct Message<'a> {
-    name: &'a str,
-    l_datum: Vec<Datum>,
+#[derive(Clone, PartialEq, Debug)]
+pub struct Message {
+    pub name: String,
+    pub l_datum: Vec<Datum>,
+}
+
+impl Message {
+    pub fn new(name: &str, l_datum: Vec<Datum>) -> Message {
+        Message {
+            name: name.to_string(),
+            l_datum: l_datum,
+        }
+    }
 }
 
-fn encode_message(message: Message) -> Vec<u8> {
-    let mut es = encode_string(&message.name.to_string());
-    let ds1 = encode_string(&descriptor(message.l_datum.clone()));
+pub fn encode_message(message: &Message) -> Vec<u8> {
+    let mut es = encode_string(&message.name);
+    let ds1 = encode_string(&descriptor(&message.l_datum));
     let mut ds2 = Vec::new();
-    for elem in message.l_datum {
-        ds2.extend(encode_datum(&elem))
+    for elem in &message.l_datum {
+        ds2.extend(encode_datum(elem))
     }
     es.extend(ds1);
     es.extend(ds2);
     es
 }
 
-fn send_message(message: Message) {
-    let bmsg = encode_message(message);
-    osc_send(bmsg);
+#[derive(Clone, PartialEq, Debug)]
+pub enum OscError {
+    Truncated,
+    InvalidString,
+    InvalidAddress(String),
+    MissingTypeTags,
+    UnknownTypeTag(char),
+    UnbalancedArray,
+    //well formed OSC, but not the arguments the named message should carry
+    UnexpectedArguments(String),
 }
 
-fn send_message_async(message: Message) {
-    let bmsg = encode_message(message);
-    osc_send_async(bmsg);
+impl fmt::Display for OscError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OscError::Truncated => write!(f, "OSC packet is truncated"),
+            OscError::InvalidString => write!(f, "OSC string is not terminated or not UTF-8"),
+            OscError::InvalidAddress(addr) => write!(f, "invalid OSC address {:?}", addr),
+            OscError::MissingTypeTags => write!(f, "OSC message has no type tag string"),
+            OscError::UnknownTypeTag(chr) => write!(f, "unknown OSC type tag '{}'", chr),
+            OscError::UnbalancedArray => write!(f, "unbalanced OSC array type tags"),
+            OscError::UnexpectedArguments(name) => write!(f, "unexpected arguments for {}", name),
+        }
+    }
 }
 
-struct PortConfig <'a>{
-    socket: Option<net::UdpSocket>,
-    addr_str: &'a str,
-    local_addr_str: &'a str,
-    tx_timeout_secs: u64,
-    rx_timeout_secs: u64,
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
 }
 
-static mut PCFG: PortConfig = PortConfig {
-    socket: None,
-    addr_str: "127.0.0.1:57110",
-    local_addr_str: "127.0.0.1:57111",
-    tx_timeout_secs: 5,
-    rx_timeout_secs: 5,
-};
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf: buf, pos: 0 }
+    }
 
-pub fn sc_start() {
-    osc_set_port();
-    let msg1 = Message {
-        name: "/notify",
-        l_datum: vec![Datum::Int(1)],
-    };
-    //b'/notify\x00,i\x00\x00\x00\x00\x00\x01'
-    send_message(msg1);
-    let msg2 = Message {
-        name: "/g_new",
-        l_datum: vec![Datum::Int(1), Datum::Int(1), Datum::Int(0)],
-    };
-    send_message(msg2);
+    fn take(&mut self, n: usize) -> Result<Vec<u8>, OscError> {
+        if self.pos + n > self.buf.len() {
+            return Err(OscError::Truncated);
+        }
+        let out = self.buf[self.pos..self.pos + n].to_vec();
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn skip_padding(&mut self) {
+        while self.pos % 4 != 0 {
+            self.pos += 1;
+        }
+    }
+
+    fn read_i32(&mut self) -> Result<i32, OscError> {
+        Ok(decode_i32(self.take(4)?))
+    }
+
+    fn read_i64(&mut self) -> Result<i64, OscError> {
+        Ok(decode_i64(self.take(8)?))
+    }
+
+    fn read_string(&mut self) -> Result<String, OscError> {
+        let rest = &self.buf[self.pos..];
+        let len = match rest.iter().position(|x| *x == 0) {
+            Some(len) => len,
+            None => return Err(OscError::InvalidString),
+        };
+        let strng = match String::from_utf8(rest[0..len].to_vec()) {
+            Ok(strng) => strng,
+            Err(_) => return Err(OscError::InvalidString),
+        };
+        self.pos += len + 1;
+        self.skip_padding();
+        if self.pos > self.buf.len() {
+            return Err(OscError::Truncated);
+        }
+        Ok(strng)
+    }
+
+    fn read_blob(&mut self) -> Result<Vec<u8>, OscError> {
+        let len = self.read_i32()?;
+        if len < 0 {
+            return Err(OscError::Truncated);
+        }
+        let out = self.take(len as usize)?;
+        self.skip_padding();
+        if self.pos > self.buf.len() {
+            return Err(OscError::Truncated);
+        }
+        Ok(out)
+    }
 
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
 }
 
-pub fn sc_stop() {
-    let msg1 = Message {
-        name: "/g_deepFree",
-        l_datum: vec![Datum::Int(1)],
+fn decode_datum(tag: char, reader: &mut Reader) -> Result<Datum, OscError> {
+    let datum = match tag {
+        'i' => Datum::Int(reader.read_i32()?),
+        'f' => Datum::Float(f32::from_bits(reader.read_i32()? as u32)),
+        's' => Datum::Str(reader.read_string()?),
+        'b' => Datum::Blob(reader.read_blob()?),
+        'h' => Datum::Int64(reader.read_i64()?),
+        'd' => Datum::Double(f64::from_bits(reader.read_i64()? as u64)),
+        't' => Datum::TimeTag(reader.read_i64()? as u64),
+        'c' => match ::std::char::from_u32(reader.read_i32()? as u32) {
+            Some(chr) => Datum::Char(chr),
+            None => return Err(OscError::InvalidString),
+        },
+        'S' => Datum::Symbol(reader.read_string()?),
+        'm' => {
+            let bb = reader.take(4)?;
+            Datum::Midi([bb[0], bb[1], bb[2], bb[3]])
+        }
+        'r' => Datum::Rgba(reader.read_i32()? as u32),
+        'T' => Datum::True,
+        'F' => Datum::False,
+        'N' => Datum::Nil,
+        'I' => Datum::Infinitum,
+        _ => return Err(OscError::UnknownTypeTag(tag)),
     };
-    send_message(msg1);
+    Ok(datum)
+}
 
-    let sleep_time = Duration::from_secs(2);
-    sleep(sleep_time);
+fn decode_data(tags: &mut ::std::str::Chars, reader: &mut Reader, nested: bool) -> Result<Vec<Datum>, OscError> {
+    let mut out = Vec::new();
+    while let Some(tag) = tags.next() {
+        match tag {
+            '[' => out.push(Datum::Array(decode_data(tags, reader, true)?)),
+            ']' => {
+                if nested {
+                    return Ok(out);
+                }
+                return Err(OscError::UnbalancedArray);
+            }
+            _ => out.push(decode_datum(tag, reader)?),
+        }
+    }
+    if nested {
+        return Err(OscError::UnbalancedArray);
+    }
+    Ok(out)
+}
 
-    osc_close_port();
+pub fn decode_message(buf: &[u8]) -> Result<Message, OscError> {
+    let mut reader = Reader::new(buf);
+    let name = reader.read_string()?;
+    if !name.starts_with('/') {
+        return Err(OscError::InvalidAddress(name));
+    }
+    //very old peers may omit the type tag string altogether
+    if reader.is_empty() {
+        return Ok(Message::new(&name, Vec::new()));
+    }
+    let tags = reader.read_string()?;
+    if !tags.starts_with(',') {
+        return Err(OscError::MissingTypeTags);
+    }
+    let l_datum = decode_data(&mut tags[1..].chars(), &mut reader, false)?;
+    Ok(Message {
+        name: name,
+        l_datum: l_datum,
+    })
 }
 
-pub fn sc_play(ugen: &Ugen) {
-    let name = "anonymous";
-    //let synd = synthdef(name, &ugen);
-    let synd = synthdef(name, &out(0, ugen));
-    let msg1 = Message {
-        name: "/d_recv",
-        l_datum: vec![Datum::Blob(synd)],
-    };
-    send_message_async(msg1);
-    let msg2 = Message {
-        name: "/s_new",
-        l_datum: vec![
-            Datum::Str(name.to_string()),
-            Datum::Int(-1),
-            Datum::Int(1),
-            Datum::Int(1),
-        ],
-    };
-    send_message(msg2);
+//seconds between the NTP epoch (1900) and the Unix epoch (1970)
+const NTP_UNIX_OFFSET: u64 = 2208988800;
+
+//64 bit NTP time: seconds since 1900 in the high word, fraction in the low word
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct TimeTag(pub u64);
+
+impl TimeTag {
+    pub const IMMEDIATELY: TimeTag = TimeTag(1);
+
+    pub fn from_system_time(time: SystemTime) -> TimeTag {
+        let since_unix = match time.duration_since(UNIX_EPOCH) {
+            Ok(dur) => dur,
+            Err(_) => Duration::new(0, 0),
+        };
+        let secs = since_unix.as_secs() + NTP_UNIX_OFFSET;
+        let frac = ((since_unix.subsec_nanos() as u64) << 32) / 1_000_000_000;
+        TimeTag((secs << 32) | frac)
+    }
+
+    pub fn from_instant(instant: Instant) -> TimeTag {
+        let now = Instant::now();
+        let sys_now = SystemTime::now();
+        if instant >= now {
+            TimeTag::from_system_time(sys_now + (instant - now))
+        } else {
+            TimeTag::from_system_time(sys_now - (now - instant))
+        }
+    }
+
+    pub fn now() -> TimeTag {
+        TimeTag::from_system_time(SystemTime::now())
+    }
+
+    //the usual way to schedule: now plus a latency covering network and scheduling jitter
+    pub fn after(latency: Duration) -> TimeTag {
+        TimeTag::from_system_time(SystemTime::now() + latency)
+    }
+
+    pub fn is_immediate(&self) -> bool {
+        *self == TimeTag::IMMEDIATELY
+    }
+
+    pub fn to_system_time(&self) -> Option<SystemTime> {
+        if self.is_immediate() {
+            return None;
+        }
+        let secs = self.0 >> 32;
+        let nanos = ((self.0 & 0xffffffff) * 1_000_000_000) >> 32;
+        if secs < NTP_UNIX_OFFSET {
+            return None;
+        }
+        Some(UNIX_EPOCH + Duration::new(secs - NTP_UNIX_OFFSET, nanos as u32))
+    }
 }
 
-pub fn sc_play_vec(ugens: Vec<Ugen>) {
-    let name = "anonymous";
-    let mut ulist = Vec::new();
-    let s_ugen: Ugen;
-    /*
-    if ulist.len() == 2 {
-        s_ugen = Ugen::Mrg(Mrg{left: Box::new(ugens[0].clone()), right: Box::new(ugens[1].clone())});
+#[derive(Clone, PartialEq, Debug)]
+pub enum Packet {
+    Message(Message),
+    Bundle(Bundle),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Bundle {
+    pub time: TimeTag,
+    pub packets: Vec<Packet>,
+}
+
+impl Bundle {
+    pub fn new(time: TimeTag, packets: Vec<Packet>) -> Bundle {
+        Bundle {
+            time: time,
+            packets: packets,
+        }
     }
-    else 
-    */
-    {
-        for ugen in ugens {
-            ulist.push(Box::new(ugen));
+}
+
+pub fn encode_bundle(bundle: &Bundle) -> Vec<u8> {
+    let mut out = encode_string(&"#bundle".to_string());
+    out.extend(encode_i64(bundle.time.0 as i64));
+    for elem in &bundle.packets {
+        let bpkt = encode_packet(elem);
+        out.extend(encode_i32(bpkt.len() as i32));
+        out.extend(bpkt);
+    }
+    out
+}
+
+pub fn encode_packet(packet: &Packet) -> Vec<u8> {
+    match packet {
+        Packet::Message(message) => encode_message(message),
+        Packet::Bundle(bundle) => encode_bundle(bundle),
+    }
+}
+
+pub fn decode_bundle(buf: &[u8]) -> Result<Bundle, OscError> {
+    let mut reader = Reader::new(buf);
+    let name = reader.read_string()?;
+    if name != "#bundle" {
+        return Err(OscError::InvalidAddress(name));
+    }
+    let time = TimeTag(reader.read_i64()? as u64);
+    let mut packets = Vec::new();
+    while !reader.is_empty() {
+        let len = reader.read_i32()?;
+        if len < 0 {
+            return Err(OscError::Truncated);
         }
-        s_ugen = Ugen::Mce(Mce{ugens: ulist});
+        let elem = reader.take(len as usize)?;
+        packets.push(decode_packet(&elem)?);
     }
-    //let synd = synthdef(name, &s_ugen);
-    let synd = synthdef(name, &out(0, &s_ugen));
-    let msg1 = Message {
-        name: "/d_recv",
-        l_datum: vec![Datum::Blob(synd)],
-    };
-    send_message(msg1);
-    let msg2 = Message {
-        name: "/s_new",
-        l_datum: vec![
-            Datum::Str(name.to_string()),
-            Datum::Int(-1),
-            Datum::Int(1),
-            Datum::Int(1),
-        ],
-    };
-    send_message(msg2);
+    Ok(Bundle::new(time, packets))
+}
+
+pub fn decode_packet(buf: &[u8]) -> Result<Packet, OscError> {
+    if buf.starts_with(b"#bundle\0") {
+        Ok(Packet::Bundle(decode_bundle(buf)?))
+    } else {
+        Ok(Packet::Message(decode_message(buf)?))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Framing {
+    //32 bit big endian size before each packet, as scsynth expects
+    LengthPrefix,
+    //OSC 1.1 SLIP, packets delimited by END bytes
+    Slip,
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Protocol {
+    Udp,
+    Tcp(Framing),
+}
 
-fn osc_set_port() {
-    let local_host: &str;
-    let tx_timeout_secs: u64;
-    let rx_timeout_secs: u64;
-    unsafe {
-        local_host = PCFG.local_addr_str;
-        tx_timeout_secs = PCFG.tx_timeout_secs;
-        rx_timeout_secs = PCFG.rx_timeout_secs;
+const SLIP_END: u8 = 0xc0;
+const SLIP_ESC: u8 = 0xdb;
+const SLIP_ESC_END: u8 = 0xdc;
+const SLIP_ESC_ESC: u8 = 0xdd;
 
+pub fn frame_packet(framing: Framing, bpkt: &[u8]) -> Vec<u8> {
+    match framing {
+        Framing::LengthPrefix => {
+            let mut out = encode_i32(bpkt.len() as i32);
+            out.extend(bpkt);
+            out
+        }
+        Framing::Slip => {
+            let mut out = vec![SLIP_END];
+            for byte in bpkt {
+                match *byte {
+                    SLIP_END => out.extend(vec![SLIP_ESC, SLIP_ESC_END]),
+                    SLIP_ESC => out.extend(vec![SLIP_ESC, SLIP_ESC_ESC]),
+                    _ => out.push(*byte),
+                }
+            }
+            out.push(SLIP_END);
+            out
+        }
     }
+}
 
-    let attempt = net::UdpSocket::bind(local_host);
-    let socket = match attempt {
-        Ok(sock) => sock,
-        Err(err) => panic!("Could not bind: {}", err),
-    };
-    socket
-        .set_write_timeout(Some(Duration::new(tx_timeout_secs, 0)))
-        .expect("Send timeout");
-    socket
-        .set_read_timeout(Some(Duration::new(rx_timeout_secs, 0)))
-        .expect("Receive timeout");
-    unsafe {
-        PCFG.socket = Some(socket);
-    }
-}
-
-fn osc_close_port() {
-    unsafe {
-        let socket = match &PCFG.socket {
-            Some(sock) => sock,
-            _ => panic!("osc_close_port"),
-        };
-        drop(socket);
-    }    
+//Takes the first complete packet out of a stream buffer, None if more bytes are needed
+pub fn unframe_packet(framing: Framing, pending: &mut Vec<u8>) -> Option<Vec<u8>> {
+    match framing {
+        Framing::LengthPrefix => {
+            if pending.len() < 4 {
+                return None;
+            }
+            let len = decode_i32(pending[0..4].to_vec()) as u32 as usize;
+            if pending.len() < 4 + len {
+                return None;
+            }
+            let out = pending[4..4 + len].to_vec();
+            pending.drain(0..4 + len);
+            Some(out)
+        }
+        Framing::Slip => loop {
+            let end = match pending.iter().position(|x| *x == SLIP_END) {
+                Some(end) => end,
+                None => return None,
+            };
+            let raw: Vec<u8> = pending.drain(0..end + 1).collect();
+            //back to back END bytes delimit an empty frame
+            if end == 0 {
+                continue;
+            }
+            let mut out = Vec::new();
+            let mut escaped = false;
+            for byte in &raw[0..end] {
+                if escaped {
+                    out.push(match *byte {
+                        SLIP_ESC_END => SLIP_END,
+                        SLIP_ESC_ESC => SLIP_ESC,
+                        other => other,
+                    });
+                    escaped = false;
+                } else if *byte == SLIP_ESC {
+                    escaped = true;
+                } else {
+                    out.push(*byte);
+                }
+            }
+            return Some(out);
+        },
+    }
 }
 
-fn osc_send(nmsg: Vec<u8>) {
-    osc_send_base(nmsg);
-    spawn(osc_receive);
+//Moves encoded packets to and from a peer. receive returns Ok(None) when nothing
+//arrived within the read timeout, so receive loops can check whether to stop.
+pub trait Transport: Send + Sync {
+    fn send(&self, bpkt: &[u8]) -> io::Result<()>;
+    fn receive(&self) -> io::Result<Option<Vec<u8>>>;
 }
 
-fn osc_send_async(nmsg: Vec<u8>) {
-    osc_send_base(nmsg);
-    let data = osc_receive();
-    //TODO check received "/done"
+fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
 }
 
-fn osc_send_base(nmsg: Vec<u8>) {
-    //SEND
-    unsafe {
-        let host = PCFG.addr_str.clone();
+//largest UDP payload
+const MAX_DATAGRAM: usize = 65536;
 
-        let socket = match &PCFG.socket {
-            Some(sock) => sock,
-            _ => panic!("osc_send socket"),
-        };
+pub struct UdpTransport {
+    socket: net::UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn connect(local_addr: &str, addr: &str, read_timeout: Duration,
+                   write_timeout: Duration) -> io::Result<UdpTransport> {
+        let socket = net::UdpSocket::bind(local_addr)?;
+        socket.set_read_timeout(Some(read_timeout))?;
+        socket.set_write_timeout(Some(write_timeout))?;
+        socket.connect(addr)?;
+        Ok(UdpTransport { socket: socket })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&self, bpkt: &[u8]) -> io::Result<()> {
+        self.socket.send(bpkt)?;
+        Ok(())
+    }
+
+    fn receive(&self) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        match self.socket.recv(&mut buf) {
+            Ok(amt) => Ok(Some(buf[0..amt].to_vec())),
+            Err(ref err) if is_timeout(err) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+pub struct TcpTransport {
+    stream: net::TcpStream,
+    framing: Framing,
+    //keeps frames from different threads from interleaving
+    write_lock: Mutex<()>,
+    pending: Mutex<Vec<u8>>,
+}
+
+impl TcpTransport {
+    pub fn new(stream: net::TcpStream, framing: Framing, read_timeout: Duration,
+               write_timeout: Duration) -> io::Result<TcpTransport> {
+        stream.set_read_timeout(Some(read_timeout))?;
+        stream.set_write_timeout(Some(write_timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(TcpTransport {
+            stream: stream,
+            framing: framing,
+            write_lock: Mutex::new(()),
+            pending: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn connect(addr: &str, framing: Framing, read_timeout: Duration,
+                   write_timeout: Duration) -> io::Result<TcpTransport> {
+        let stream = net::TcpStream::connect(addr)?;
+        TcpTransport::new(stream, framing, read_timeout, write_timeout)
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, bpkt: &[u8]) -> io::Result<()> {
+        let _lock = self.write_lock.lock().unwrap();
+        (&self.stream).write_all(&frame_packet(self.framing, bpkt))
+    }
+
+    fn receive(&self) -> io::Result<Option<Vec<u8>>> {
+        let mut pending = self.pending.lock().unwrap();
+        let mut buf = vec![0; 4096];
+        loop {
+            if let Some(bpkt) = unframe_packet(self.framing, &mut pending) {
+                return Ok(Some(bpkt));
+            }
+            match (&self.stream).read(&mut buf) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")),
+                Ok(amt) => pending.extend_from_slice(&buf[0..amt]),
+                Err(ref err) if is_timeout(err) => return Ok(None),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+//OSC address pattern matching. Wildcards never match across a '/'.
+fn match_chars(pattern: &[char], address: &[char]) -> bool {
+    if pattern.len() == 0 {
+        return address.len() == 0;
+    }
+    match pattern[0] {
+        '*' => {
+            for ind in 0..address.len() + 1 {
+                if match_chars(&pattern[1..], &address[ind..]) {
+                    return true;
+                }
+                if ind < address.len() && address[ind] == '/' {
+                    break;
+                }
+            }
+            false
+        }
+        '?' => {
+            address.len() > 0 && address[0] != '/' && match_chars(&pattern[1..], &address[1..])
+        }
+        '[' => {
+            let close = match pattern.iter().skip(1).position(|x| *x == ']') {
+                Some(pos) => pos + 1,
+                None => return false,
+            };
+            if address.len() == 0 || address[0] == '/' {
+                return false;
+            }
+            let mut set = &pattern[1..close];
+            let negate = set.len() > 0 && set[0] == '!';
+            if negate {
+                set = &set[1..];
+            }
+            let chr = address[0];
+            let mut found = false;
+            let mut ind = 0;
+            while ind < set.len() {
+                if ind + 2 < set.len() && set[ind + 1] == '-' {
+                    if set[ind] <= chr && chr <= set[ind + 2] {
+                        found = true;
+                    }
+                    ind += 3;
+                } else {
+                    if set[ind] == chr {
+                        found = true;
+                    }
+                    ind += 1;
+                }
+            }
+            found != negate && match_chars(&pattern[close + 1..], &address[1..])
+        }
+        '{' => {
+            let close = match pattern.iter().position(|x| *x == '}') {
+                Some(pos) => pos,
+                None => return false,
+            };
+            let rest = &pattern[close + 1..];
+            for alt in pattern[1..close].split(|x| *x == ',') {
+                if address.starts_with(alt) && match_chars(rest, &address[alt.len()..]) {
+                    return true;
+                }
+            }
+            false
+        }
+        chr => address.len() > 0 && address[0] == chr && match_chars(&pattern[1..], &address[1..]),
+    }
+}
+
+pub fn osc_match(pattern: &str, address: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let address: Vec<char> = address.chars().collect();
+    match_chars(&pattern, &address)
+}
+
+pub type HandlerId = usize;
+
+struct HandlerEntry {
+    id: HandlerId,
+    key: Option<String>,
+    pattern: String,
+    handler: Arc<dyn Fn(&Message) + Send + Sync>,
+}
+
+//Calls every handler whose pattern matches an incoming address, like sclang's OSCdef
+pub struct OscDispatcher {
+    handlers: Mutex<Vec<HandlerEntry>>,
+    next_id: AtomicUsize,
+}
+
+impl OscDispatcher {
+    pub fn new() -> OscDispatcher {
+        OscDispatcher {
+            handlers: Mutex::new(Vec::new()),
+            next_id: AtomicUsize::new(0),
+        }
+    }
+
+    fn push<F>(&self, key: Option<String>, pattern: &str, handler: F) -> HandlerId
+    where
+        F: Fn(&Message) + Send + Sync + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut handlers = self.handlers.lock().unwrap();
+        if key.is_some() {
+            handlers.retain(|x| x.key != key);
+        }
+        handlers.push(HandlerEntry {
+            id: id,
+            key: key,
+            pattern: pattern.to_string(),
+            handler: Arc::new(handler),
+        });
+        id
+    }
+
+    pub fn add<F>(&self, pattern: &str, handler: F) -> HandlerId
+    where
+        F: Fn(&Message) + Send + Sync + 'static,
+    {
+        self.push(None, pattern, handler)
+    }
+
+    //registers under a key, replacing whatever was registered with that key before
+    pub fn def<F>(&self, key: &str, pattern: &str, handler: F) -> HandlerId
+    where
+        F: Fn(&Message) + Send + Sync + 'static,
+    {
+        self.push(Some(key.to_string()), pattern, handler)
+    }
+
+    pub fn remove(&self, id: HandlerId) -> bool {
+        let mut handlers = self.handlers.lock().unwrap();
+        let len = handlers.len();
+        handlers.retain(|x| x.id != id);
+        handlers.len() != len
+    }
+
+    pub fn remove_def(&self, key: &str) -> bool {
+        let mut handlers = self.handlers.lock().unwrap();
+        let len = handlers.len();
+        handlers.retain(|x| x.key.as_ref().map(|k| k.as_str()) != Some(key));
+        handlers.len() != len
+    }
+
+    //returns false when no handler matched
+    pub fn dispatch(&self, message: &Message) -> bool {
+        //handlers run unlocked so they may add or remove handlers themselves
+        let matching: Vec<Arc<dyn Fn(&Message) + Send + Sync>> = self.handlers.lock().unwrap()
+            .iter()
+            .filter(|x| osc_match(&x.pattern, &message.name))
+            .map(|x| x.handler.clone())
+            .collect();
+        for handler in &matching {
+            handler(message);
+        }
+        matching.len() > 0
+    }
+}
+
+//how often receive threads wake up to check whether their owner was dropped
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+//What an OscListener passes to its error handler instead of stopping
+#[derive(Debug)]
+pub enum ListenerError {
+    Io(io::Error),
+    //a packet that did not decode
+    Osc(OscError),
+}
+
+impl fmt::Display for ListenerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenerError::Io(err) => write!(f, "OscListener I/O error: {}", err),
+            ListenerError::Osc(err) => write!(f, "OscListener dropped a packet: {}", err),
+        }
+    }
+}
+
+type ErrorHandler = Arc<Mutex<Option<Box<dyn Fn(&ListenerError) + Send>>>>;
 
-        let result = &socket.send_to(&nmsg, host);
-        //drop(socket);
-        match result {
-            Ok(amt) => {
-                //println!("Sent {} bytes", amt);
-                print_bytes("Txed data:", &nmsg);
-            },
-            Err(err) => panic!("Write error: {}", err),
+fn report(errors: &ErrorHandler, err: ListenerError) {
+    if let Some(ref handler) = *errors.lock().unwrap() {
+        handler(&err);
+    }
+}
+
+//Holds bundle contents back until their timetag, then hands them to the dispatcher
+fn schedule_loop(rx: Receiver<(TimeTag, Message)>, dispatcher: Arc<OscDispatcher>, running: Arc<AtomicBool>) {
+    let mut queue: Vec<(TimeTag, Message)> = Vec::new();
+    while running.load(Ordering::SeqCst) {
+        let now = TimeTag::now();
+        while queue.len() > 0 && queue[0].0 <= now {
+            let (_, message) = queue.remove(0);
+            dispatcher.dispatch(&message);
+        }
+        let mut wait = POLL_INTERVAL;
+        if let Some(&(time, _)) = queue.first() {
+            if let Some(due) = time.to_system_time() {
+                if let Ok(until) = due.duration_since(SystemTime::now()) {
+                    if until < wait {
+                        wait = until;
+                    }
+                }
+            }
+        }
+        match rx.recv_timeout(wait) {
+            Ok((time, message)) => {
+                //equal times keep their arrival order
+                let pos = queue.iter().position(|x| x.0 > time).unwrap_or(queue.len());
+                queue.insert(pos, (time, message));
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
         }
     }
 }
 
-fn osc_receive() -> Vec<u8>{
-    unsafe {
-        let socket = match &PCFG.socket {
-            Some(sock) => sock,
-            _ => panic!("osc_send"),
+fn handle_packet(packet: Packet, time: TimeTag, dispatcher: &OscDispatcher,
+                 scheduler: &Sender<(TimeTag, Message)>) {
+    match packet {
+        Packet::Message(message) => {
+            if time.is_immediate() || time <= TimeTag::now() {
+                dispatcher.dispatch(&message);
+            } else {
+                //only fails once the listener is shutting down
+                let _ = scheduler.send((time, message));
+            }
+        }
+        Packet::Bundle(bundle) => {
+            //a nested bundle may not run before the bundle enclosing it
+            let inner = if bundle.time.is_immediate() || bundle.time < time { time } else { bundle.time };
+            for elem in bundle.packets {
+                handle_packet(elem, inner, dispatcher, scheduler);
+            }
+        }
+    }
+}
+
+fn listen_loop(transport: Arc<dyn Transport>, dispatcher: Arc<OscDispatcher>,
+               scheduler: Sender<(TimeTag, Message)>, running: Arc<AtomicBool>, errors: ErrorHandler) {
+    while running.load(Ordering::SeqCst) {
+        let bpkt = match transport.receive() {
+            Ok(Some(bpkt)) => bpkt,
+            Ok(None) => continue,
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => {
+                report(&errors, ListenerError::Io(err));
+                continue;
+            }
         };
-        //RECEIVE
-        //let mut buf: [u8; 1] = [0; 1];
-        let mut buf: [u8; 1024] = mem::uninitialized();
-        println!("Reading data ....");
-        let result = socket.recv_from(&mut buf);
-        //drop(socket);
-        let data = match result {
-            Ok((amt, src)) => {
-                println!("Received data from {}", src);
-                Vec::from(&buf[0..amt])                
+        match decode_packet(&bpkt) {
+            Ok(packet) => handle_packet(packet, TimeTag::IMMEDIATELY, &dispatcher, &scheduler),
+            Err(err) => report(&errors, ListenerError::Osc(err)),
+        }
+    }
+}
+
+fn accept_loop(listener: net::TcpListener, framing: Framing, dispatcher: Arc<OscDispatcher>,
+               scheduler: Sender<(TimeTag, Message)>, running: Arc<AtomicBool>, errors: ErrorHandler) {
+    while running.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let transport = stream.set_nonblocking(false)
+                    .and_then(|_| TcpTransport::new(stream, framing, POLL_INTERVAL, POLL_INTERVAL));
+                match transport {
+                    Ok(transport) => {
+                        let transport: Arc<dyn Transport> = Arc::new(transport);
+                        let dispatcher = dispatcher.clone();
+                        let scheduler = scheduler.clone();
+                        let running = running.clone();
+                        let errors = errors.clone();
+                        spawn(move || listen_loop(transport, dispatcher, scheduler, running, errors));
+                    }
+                    Err(err) => report(&errors, ListenerError::Io(err)),
+                }
+            }
+            Err(ref err) if is_timeout(err) => sleep(POLL_INTERVAL),
+            //errors like running out of file descriptors last, wait before trying again
+            Err(err) => {
+                report(&errors, ListenerError::Io(err));
+                sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+//Receives OSC from any peer, controllers and other applications included, and
+//passes it to the handlers of its dispatcher. Stops listening when dropped.
+pub struct OscListener {
+    local_addr: net::SocketAddr,
+    dispatcher: Arc<OscDispatcher>,
+    running: Arc<AtomicBool>,
+    errors: ErrorHandler,
+}
+
+impl OscListener {
+    pub fn bind(addr: &str, protocol: Protocol) -> io::Result<OscListener> {
+        let dispatcher = Arc::new(OscDispatcher::new());
+        let running = Arc::new(AtomicBool::new(true));
+        let errors: ErrorHandler = Arc::new(Mutex::new(None));
+        let (tx, rx) = channel();
+        let local_addr = match protocol {
+            Protocol::Udp => {
+                let socket = net::UdpSocket::bind(addr)?;
+                socket.set_read_timeout(Some(POLL_INTERVAL))?;
+                let local_addr = socket.local_addr()?;
+                let transport: Arc<dyn Transport> = Arc::new(UdpListenTransport { socket: socket });
+                let dispatcher = dispatcher.clone();
+                let running = running.clone();
+                let errors = errors.clone();
+                spawn(move || listen_loop(transport, dispatcher, tx, running, errors));
+                local_addr
             }
-            Err(_) => {
-                println!("Timeout receive error");
-                Vec::new()
+            Protocol::Tcp(framing) => {
+                let listener = net::TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                let local_addr = listener.local_addr()?;
+                let dispatcher = dispatcher.clone();
+                let running = running.clone();
+                let errors = errors.clone();
+                spawn(move || accept_loop(listener, framing, dispatcher, tx, running, errors));
+                local_addr
             }
         };
-        print_bytes("Received data:", &data);
-        return data;
+        let sched_dispatcher = dispatcher.clone();
+        let sched_running = running.clone();
+        spawn(move || schedule_loop(rx, sched_dispatcher, sched_running));
+        Ok(OscListener {
+            local_addr: local_addr,
+            dispatcher: dispatcher,
+            running: running,
+            errors: errors,
+        })
+    }
+
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.local_addr
     }
+
+    pub fn dispatcher(&self) -> &OscDispatcher {
+        &self.dispatcher
+    }
+
+    //Called from the listener threads for receive errors and packets that were
+    //dropped. Without a handler they are ignored and listening goes on.
+    pub fn on_error<F>(&self, handler: F)
+    where
+        F: Fn(&ListenerError) + Send + 'static,
+    {
+        *self.errors.lock().unwrap() = Some(Box::new(handler));
+    }
+}
+
+impl Drop for OscListener {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+//an unconnected socket, taking datagrams from any sender
+struct UdpListenTransport {
+    socket: net::UdpSocket,
+}
+
+impl Transport for UdpListenTransport {
+    fn send(&self, _bpkt: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::NotConnected, "listener socket has no peer"))
+    }
+
+    fn receive(&self) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        match self.socket.recv_from(&mut buf) {
+            Ok((amt, _)) => Ok(Some(buf[0..amt].to_vec())),
+            Err(ref err) if is_timeout(err) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[test]
+fn test_decode_message() {
+    assert_eq!(decode_i32(encode_i32(-123456)), -123456);
+    assert_eq!(decode_i32(vec![0, 0, 1, 2]), 258);
+    assert_eq!(decode_i64(encode_i64(-1 << 40)), -1 << 40);
+    let msg = Message::new("/test", vec![
+        Datum::Int(-7),
+        Datum::Float(0.5),
+        Datum::Str("abcd".to_string()),
+        Datum::Blob(vec![1, 2, 3, 4]),
+        Datum::Blob(vec![5]),
+        Datum::Int64(1 << 40),
+        Datum::Double(1.25),
+        Datum::TimeTag(1),
+        Datum::Char('x'),
+        Datum::Symbol("sym".to_string()),
+        Datum::Midi([0, 0x90, 60, 100]),
+        Datum::Rgba(0xff00ff00),
+        Datum::Array(vec![Datum::Int(1), Datum::Array(vec![Datum::True])]),
+        Datum::False,
+        Datum::Nil,
+        Datum::Infinitum,
+    ]);
+    let bmsg = encode_message(&msg);
+    assert_eq!(bmsg.len() % 4, 0);
+    assert_eq!(decode_message(&bmsg), Ok(msg));
+    assert_eq!(decode_message(&bmsg[0..bmsg.len() - 4]), Err(OscError::Truncated));
+    let unbalanced = encode_message(&Message::new("/x", vec![]));
+    let mut bad = unbalanced[0..4].to_vec();
+    bad.extend(encode_string(&",[i".to_string()));
+    bad.extend(encode_i32(1));
+    assert_eq!(decode_message(&bad), Err(OscError::UnbalancedArray));
+}
+
+#[test]
+fn test_bundle() {
+    let inner = Bundle::new(TimeTag::IMMEDIATELY, vec![
+        Packet::Message(Message::new("/n_set", vec![Datum::Int(1000), Datum::Str("freq".to_string()), Datum::Float(220.0)])),
+    ]);
+    let time = TimeTag::after(Duration::from_millis(200));
+    let outer = Bundle::new(time, vec![
+        Packet::Message(Message::new("/s_new", vec![Datum::Str("default".to_string()), Datum::Int(1000)])),
+        Packet::Bundle(inner),
+    ]);
+    let bb = encode_bundle(&outer);
+    assert_eq!(bb.len() % 4, 0);
+    assert_eq!(decode_packet(&bb), Ok(Packet::Bundle(outer)));
+    assert_eq!(&encode_bundle(&Bundle::new(TimeTag::IMMEDIATELY, vec![]))[8..16], &[0, 0, 0, 0, 0, 0, 0, 1]);
+
+    let now = SystemTime::now();
+    let back = TimeTag::from_system_time(now).to_system_time().unwrap();
+    let diff = match now.duration_since(back) {
+        Ok(dur) => dur,
+        Err(err) => err.duration(),
+    };
+    assert!(diff < Duration::from_micros(1));
+    assert!(TimeTag::from_instant(Instant::now() + Duration::from_secs(1)) > TimeTag::now());
+    assert_eq!(TimeTag::IMMEDIATELY.to_system_time(), None);
+}
+
+#[test]
+fn test_framing() {
+    let bpkt = vec![1, SLIP_END, 2, SLIP_ESC, 3];
+    for framing in vec![Framing::LengthPrefix, Framing::Slip] {
+        let mut pending = frame_packet(framing, &bpkt);
+        pending.extend(frame_packet(framing, &[4, 5, 6, 7]));
+        let tail = pending.split_off(pending.len() - 2);
+        assert_eq!(unframe_packet(framing, &mut pending), Some(bpkt.clone()));
+        assert_eq!(unframe_packet(framing, &mut pending), None);
+        pending.extend(tail);
+        assert_eq!(unframe_packet(framing, &mut pending), Some(vec![4, 5, 6, 7]));
+        assert_eq!(pending.len(), 0);
+    }
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let timeout = Duration::from_millis(500);
+    let client = TcpTransport::connect(&addr, Framing::LengthPrefix, timeout, timeout).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let peer = TcpTransport::new(stream, Framing::LengthPrefix, timeout, timeout).unwrap();
+    let big = encode_message(&Message::new("/d_recv", vec![Datum::Blob(vec![7; 200000])]));
+    client.send(&big).unwrap();
+    let mut got = None;
+    while got.is_none() {
+        got = peer.receive().unwrap();
+    }
+    assert_eq!(got, Some(big));
+    assert_eq!(peer.receive().unwrap(), None);
+}
+
+#[test]
+fn test_dispatcher() {
+    assert!(osc_match("/n_go", "/n_go"));
+    assert!(!osc_match("/n_go", "/n_goo"));
+    assert!(osc_match("/n_*", "/n_end"));
+    assert!(osc_match("/*/fader?", "/mixer/fader3"));
+    assert!(!osc_match("/*", "/mixer/fader3"));
+    assert!(osc_match("/mixer/fader[1-4]", "/mixer/fader3"));
+    assert!(!osc_match("/mixer/fader[!1-4]", "/mixer/fader3"));
+    assert!(osc_match("/mixer/fader[!1-4]", "/mixer/fader8"));
+    assert!(osc_match("/mixer/{fader,knob}[0-9]", "/mixer/knob0"));
+    assert!(!osc_match("/mixer/{fader,knob}", "/mixer/button"));
+    assert!(osc_match("/*/*", "/a/b"));
+
+    let dispatcher = OscDispatcher::new();
+    let count = Arc::new(AtomicUsize::new(0));
+    let count1 = count.clone();
+    let id = dispatcher.add("/n_{go,end}", move |_| { count1.fetch_add(1, Ordering::SeqCst); });
+    let count2 = count.clone();
+    dispatcher.def("tr", "/tr", move |_| { count2.fetch_add(10, Ordering::SeqCst); });
+    let count3 = count.clone();
+    dispatcher.def("tr", "/tr", move |_| { count3.fetch_add(100, Ordering::SeqCst); });
+    assert!(dispatcher.dispatch(&Message::new("/n_go", vec![])));
+    assert!(dispatcher.dispatch(&Message::new("/tr", vec![])));
+    assert!(!dispatcher.dispatch(&Message::new("/n_move", vec![])));
+    assert_eq!(count.load(Ordering::SeqCst), 101);
+    assert!(dispatcher.remove(id));
+    assert!(dispatcher.remove_def("tr"));
+    assert!(!dispatcher.dispatch(&Message::new("/n_end", vec![])));
+}
+
+#[test]
+fn test_listener() {
+    for protocol in vec![Protocol::Udp, Protocol::Tcp(Framing::Slip)] {
+        let listener = OscListener::bind("127.0.0.1:0", protocol).unwrap();
+        let (tx, rx) = channel();
+        let tx = Mutex::new(tx);
+        listener.dispatcher().add("/fader/*", move |message| {
+            tx.lock().unwrap().send((message.clone(), Instant::now())).unwrap();
+        });
+        let later = Bundle::new(TimeTag::after(Duration::from_millis(300)), vec![
+            Packet::Message(Message::new("/fader/2", vec![Datum::Float(0.2)])),
+            Packet::Bundle(Bundle::new(TimeTag::IMMEDIATELY, vec![
+                Packet::Message(Message::new("/fader/3", vec![Datum::Float(0.3)])),
+            ])),
+        ]);
+        let now = Message::new("/fader/1", vec![Datum::Float(0.1)]);
+        let start = Instant::now();
+        let addr = listener.local_addr().to_string();
+        let peer: Box<dyn Transport> = match protocol {
+            Protocol::Udp => Box::new(UdpTransport::connect("127.0.0.1:0", &addr, POLL_INTERVAL, POLL_INTERVAL).unwrap()),
+            Protocol::Tcp(framing) => Box::new(TcpTransport::connect(&addr, framing, POLL_INTERVAL, POLL_INTERVAL).unwrap()),
+        };
+        peer.send(&encode_bundle(&later)).unwrap();
+        peer.send(&encode_message(&Message::new("/other", vec![]))).unwrap();
+        peer.send(&encode_message(&now)).unwrap();
+        let timeout = Duration::from_secs(2);
+        let (first, _) = rx.recv_timeout(timeout).unwrap();
+        assert_eq!(first, now);
+        let (second, at) = rx.recv_timeout(timeout).unwrap();
+        assert_eq!(second.name, "/fader/2");
+        assert!(at - start >= Duration::from_millis(250));
+        let (third, _) = rx.recv_timeout(timeout).unwrap();
+        assert_eq!(third.name, "/fader/3");
+    }
+}
+
+#[test]
+fn test_listener_errors() {
+    let listener = OscListener::bind("127.0.0.1:0", Protocol::Udp).unwrap();
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    listener.on_error(move |err| tx.lock().unwrap().send(err.to_string()).unwrap());
+    let peer = UdpTransport::connect("127.0.0.1:0", &listener.local_addr().to_string(),
+                                     POLL_INTERVAL, POLL_INTERVAL).unwrap();
+    peer.send(b"junk").unwrap();
+    let err = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    assert!(err.starts_with("OscListener dropped a packet"));
 }
@@ -25,6 +25,7 @@ pub struct Control {
     name: String,
     index: i32,
     rate: Rate,
+    default: f32,
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -78,7 +79,7 @@ struct NodeK {
     id: i32,
     name: String,
     rate: Rate,
-    def: i32,
+    def: f32,
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -393,10 +394,11 @@ fn max_num(nums: Vec<i32>, start: i32) -> i32 {
     max
 }
 
+//RateDr inputs are pulled by the ugen reading them, so they never raise its rate
 fn max_rate(rates: RateList, start: Rate) -> Rate {
     let mut max = start;
     for elem in rates {
-        if elem as i32 > max as i32 {
+        if elem != Rate::RateDr && elem as i32 > max as i32 {
             max = elem;
         }
     }
@@ -652,7 +654,7 @@ fn mk_ugen(
 fn node_c_value(nodec: &NodeC) -> f32 {
     nodec.value
 }
-fn node_k_default(nodek: &NodeK) -> i32 {
+fn node_k_default(nodek: &NodeK) -> f32 {
     nodek.def
 }
 
@@ -751,7 +753,7 @@ fn push_k(ctrl: &Control, gr: &Graph) -> (Node, Graph) {
     let node = NodeK {
         id: gr.next_id + 1,
         name: ctrl.name.clone(),
-        def: ctrl.index,
+        def: ctrl.default,
         rate: ctrl.rate,
     };
     let mut contrs = vec![node.clone()];
@@ -949,6 +951,88 @@ fn prepare_root(ugen: &Ugen) -> Ugen {
     }
 }
 
+fn collect_local_bufs(ugen: &Ugen, found: &mut Vec<Ugen>) {
+    match ugen {
+        Ugen::Primitive(primitive) => {
+            if primitive.name == "LocalBuf" {
+                if found.iter().filter(|x| compare_ugen(x, ugen)).count() == 0 {
+                    found.push(ugen.clone());
+                }
+            }
+            for elem in &primitive.inputs {
+                collect_local_bufs(elem, found);
+            }
+        }
+        Ugen::Mce(mce) => {
+            for elem in &mce.ugens {
+                collect_local_bufs(elem, found);
+            }
+        }
+        Ugen::Mrg(mrg) => {
+            collect_local_bufs(&mrg.left, found);
+            collect_local_bufs(&mrg.right, found);
+        }
+        Ugen::Proxy(proxy) => collect_local_bufs(&Ugen::Primitive(proxy.primitive.clone()), found),
+        _ => {}
+    }
+}
+
+fn append_max_local_bufs(ugen: &Ugen, max_bufs: &Ugen) -> Ugen {
+    match ugen {
+        Ugen::Primitive(primitive) => {
+            let mut inputs: UgenList = Vec::new();
+            for elem in &primitive.inputs {
+                inputs.push(Box::new(append_max_local_bufs(elem, max_bufs)));
+            }
+            if primitive.name == "LocalBuf" {
+                inputs.push(Box::new(max_bufs.clone()));
+            }
+            Ugen::Primitive(Primitive {
+                inputs: inputs,
+                ..primitive.clone()
+            })
+        }
+        Ugen::Mce(mce) => {
+            let mut lst: UgenList = Vec::new();
+            for elem in &mce.ugens {
+                lst.push(Box::new(append_max_local_bufs(elem, max_bufs)));
+            }
+            Ugen::Mce(Mce { ugens: lst })
+        }
+        Ugen::Mrg(mrg) => Ugen::Mrg(Mrg {
+            left: Box::new(append_max_local_bufs(&mrg.left, max_bufs)),
+            right: Box::new(append_max_local_bufs(&mrg.right, max_bufs)),
+        }),
+        Ugen::Proxy(proxy) => {
+            match append_max_local_bufs(&Ugen::Primitive(proxy.primitive.clone()), max_bufs) {
+                Ugen::Primitive(primitive) => Ugen::Proxy(Proxy {
+                    primitive: primitive,
+                    index: proxy.index,
+                }),
+                _ => panic!("append_max_local_bufs"),
+            }
+        }
+        _ => ugen.clone(),
+    }
+}
+
+//scsynth needs a MaxLocalBufs ugen, ahead of every LocalBuf, holding their count
+fn add_max_local_bufs(ugen: &Ugen) -> Ugen {
+    let mut found = Vec::new();
+    collect_local_bufs(ugen, &mut found);
+    if found.len() == 0 {
+        return ugen.clone();
+    }
+    let max_bufs = Ugen::Primitive(Primitive {
+        name: "MaxLocalBufs".to_string(),
+        inputs: vec![iconst(found.len() as i32)],
+        outputs: vec![Rate::RateIr],
+        rate: Rate::RateIr,
+        ..Primitive::default()
+    });
+    append_max_local_bufs(ugen, &max_bufs)
+}
+
 fn empty_graph() -> Graph {
     Graph {
         next_id: 0,
@@ -959,7 +1043,7 @@ fn empty_graph() -> Graph {
 }
 
 fn synth(ugen: &Ugen) -> Graph {
-    let root = prepare_root(ugen);
+    let root = prepare_root(&add_max_local_bufs(ugen));
     //print_ugen(0, &root); //DEBUG
     let (_, gr) = mk_node(&root, &empty_graph());
     let cs = gr.constants.clone();
@@ -1053,7 +1137,7 @@ fn encode_graphdef(name: &String, graph: &Graph) -> Vec<u8> {
     }
     let mut a7 = Vec::new();
     for elem in l2 {
-        a7.extend(encode_f32(elem as f32));
+        a7.extend(encode_f32(elem));
     }
     out.extend(a7);
     out.extend(encode_i16(graph.controls.len() as i32));
@@ -1073,6 +1157,176 @@ pub fn synthdef(name: &str, ugen: &Ugen) -> Vec<u8> {
     eg
 }
 
+//an encoded def with a hash of its content, so a server can tell it has it already
+#[derive(Clone, PartialEq, Debug)]
+pub struct SynthDef {
+    name: String,
+    data: Vec<u8>,
+    hash: u64,
+}
+
+//FNV-1a, spelled out because std's hashers may change between Rust releases
+//and anonymous def names have to stay the same
+fn hash_bytes(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+impl SynthDef {
+    pub fn new(name: &str, ugen: &Ugen) -> SynthDef {
+        SynthDef::from_data(name, encode_graphdef(&name.to_string(), &synth(ugen)))
+    }
+
+    //named after the hash of the graph, equal graphs share one def on the server
+    pub fn anonymous(ugen: &Ugen) -> SynthDef {
+        let graph = synth(ugen);
+        let hash = hash_bytes(&encode_graphdef(&String::new(), &graph));
+        let name = format!("rsc3_{:016x}", hash);
+        SynthDef::from_data(&name, encode_graphdef(&name, &graph))
+    }
+
+    pub fn from_data(name: &str, data: Vec<u8>) -> SynthDef {
+        SynthDef {
+            name: name.to_string(),
+            hash: hash_bytes(&data),
+            data: data,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+}
+
+//what a def offers to players and UIs, read from the compiled graph
+#[derive(Clone, PartialEq, Debug)]
+pub struct SynthDesc {
+    pub name: String,
+    //in control index order, as /n_set and /s_new number them
+    pub controls: Vec<ControlDesc>,
+    pub outputs: Vec<OutputDesc>,
+    pub has_gate: bool,
+    //some ugen frees the synth with a doneAction of 2 or more, or one set by a control
+    pub can_free_synth: bool,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ControlDesc {
+    pub name: String,
+    pub index: i32,
+    pub default: f32,
+    pub rate: Rate,
+}
+
+//one Out, ReplaceOut, OffsetOut or XOut
+#[derive(Clone, PartialEq, Debug)]
+pub struct OutputDesc {
+    pub rate: Rate,
+    pub bus: OutputBus,
+    pub channels: i32,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum OutputBus {
+    Fixed(i32),
+    Control(String),
+    //computed by other ugens
+    Signal,
+}
+
+//index of the doneAction input of ugens that can end their synth
+fn done_action_input(name: &str) -> Option<usize> {
+    match name {
+        "Line" | "XLine" | "DetectSilence" => Some(3),
+        "EnvGen" | "Linen" => Some(4),
+        "PlayBuf" => Some(5),
+        "RecordBuf" => Some(7),
+        "Duty" | "TDuty" => Some(2),
+        "DemandEnvGen" => Some(9),
+        _ => None,
+    }
+}
+
+pub fn synthdesc(name: &str, ugen: &Ugen) -> SynthDesc {
+    let graph = synth(ugen);
+    let mut controls = Vec::new();
+    for (index, node) in graph.controls.iter().enumerate() {
+        controls.push(ControlDesc {
+            name: node.name.clone(),
+            index: index as i32,
+            default: node.def,
+            rate: node.rate,
+        });
+    }
+    let constant = |input: &Ugen| match input {
+        Ugen::FromPortC(fc) => graph.constants.iter().find(|x| x.id == fc.port_nid).map(|x| x.value),
+        _ => None,
+    };
+    let mut outputs = Vec::new();
+    let mut can_free_synth = false;
+    for node in graph.ugens.iter() {
+        let skip = match node.name.as_str() {
+            "Out" | "ReplaceOut" | "OffsetOut" => 1,
+            "XOut" => 2,
+            "FreeSelf" | "FreeSelfWhenDone" => {
+                can_free_synth = true;
+                continue;
+            }
+            name => {
+                if let Some(input) = done_action_input(name).and_then(|i| node.inputs.get(i)) {
+                    can_free_synth |= constant(input).map_or(true, |x| x >= 2.0);
+                }
+                continue;
+            }
+        };
+        if node.inputs.is_empty() {
+            continue;
+        }
+        let bus = match *node.inputs[0] {
+            Ugen::FromPortC(_) => OutputBus::Fixed(constant(&node.inputs[0]).unwrap_or(0.0) as i32),
+            Ugen::FromPortK(ref fk) => match graph.controls.iter().find(|x| x.id == fk.port_nid) {
+                Some(control) => OutputBus::Control(control.name.clone()),
+                None => OutputBus::Signal,
+            },
+            _ => OutputBus::Signal,
+        };
+        outputs.push(OutputDesc {
+            rate: node.rate,
+            bus: bus,
+            channels: (node.inputs.len() as i32 - skip).max(0),
+        });
+    }
+    SynthDesc {
+        name: name.to_string(),
+        has_gate: controls.iter().any(|x| x.name == "gate"),
+        controls: controls,
+        outputs: outputs,
+        can_free_synth: can_free_synth,
+    }
+}
+
+//named control input, settable per node with /n_set
+pub fn mk_control(rate: Rate, name: &str, default: f32) -> Ugen {
+    Ugen::Control(Control {
+        name: name.to_string(),
+        index: 0,
+        rate: rate,
+        default: default,
+    })
+}
+
 pub fn mk_osc_mce(rate: Rate, name: &str, inputs: UgenList, ugen: &Ugen, ou: i32) -> Ugen {
     let mut rl = Vec::new();
     for _ in 0..ou {
@@ -1085,6 +1339,17 @@ pub fn mk_osc_mce(rate: Rate, name: &str, inputs: UgenList, ugen: &Ugen, ou: i32
     mk_ugen(rate, &name.to_string(), inps, rl, 0, 0)
 }
 
+pub fn mk_osc_mce_id(rate: Rate, name: &str, inputs: UgenList, ugen: &Ugen, ou: i32) -> Ugen {
+    let mut rl = Vec::new();
+    for _ in 0..ou {
+        rl.push(rate);
+    }
+    let mut inps = Vec::new();
+    inps.extend(inputs);
+    inps.extend(mce_channels(ugen));
+    mk_ugen(rate, &name.to_string(), inps, rl, next_uid(), 0)
+}
+
 pub fn mk_osc_id(rate: Rate, name: &str, inputs: UgenList, ou: i32) -> Ugen {
     let mut rl = Vec::new();
     for _ in 0..ou {
@@ -1131,9 +1396,11 @@ pub fn mk_filter_mce(name: &str, inputs: UgenList, ugen: &Ugen, ou: i32) -> Ugen
     mk_filter(name, inps, ou)
 }
 
+//Math on a RateDr input is demand rate too, as in sclang, so it runs each time
+//the consumer pulls it. Filters have no demand calc function in scsynth.
 pub fn mk_operator(name: &str, inputs: UgenList, sp: i32) -> Ugen {
-    let rates = inputs.clone().into_iter().map(|x| rate_of(&x)).collect();
-    let maxrate = max_rate(rates, Rate::RateKr);
+    let rates: RateList = inputs.clone().into_iter().map(|x| rate_of(&x)).collect();
+    let maxrate = if rates.contains(&Rate::RateDr) { Rate::RateDr } else { max_rate(rates, Rate::RateKr) };
     let outs = vec![maxrate];
     mk_ugen(maxrate, &name.to_string(), inputs, outs, 0, sp)
 }
@@ -1390,13 +1657,13 @@ fn test1() {
     let b1 = encode_i16(125);
     let ndk1 = Node::NodeK(NodeK {
         name: "ndk1".to_string(),
-        def: 5,
+        def: 5.0,
         id: 30,
         rate: Rate::RateKr,
     });
     let ndk2 = Node::NodeK(NodeK {
         name: "ndk2".to_string(),
-        def: 5,
+        def: 5.0,
         id: 31,
         rate: Rate::RateKr,
     });
@@ -1437,6 +1704,7 @@ fn test1() {
         name: "ndk1".to_string(),
         rate: Rate::RateKr,
         index: 3,
+        default: 0.0,
     });
     let (nn11, _) = mk_node_k(&ck1, &gr1);
     let nnk11 = get_node_k(&nn11);
@@ -1471,3 +1739,86 @@ fn test1() {
     
 
 }
+
+#[test]
+fn test_max_local_bufs() {
+    use ugens::{c, local_buf, fft, ifft, pv_brick_wall, pv_mul, out};
+    let chain1 = fft(local_buf(c(2048.0), c(1.0)), c(0.0), c(0.5), c(0.0), c(1.0), c(0.0));
+    let chain2 = fft(local_buf(c(2048.0), c(1.0)), c(1.0), c(0.5), c(0.0), c(1.0), c(0.0));
+    let chain = pv_mul(pv_brick_wall(chain1, c(0.1)), chain2);
+    let gr = synth(&out(0, &ifft(Rate::RateAr, chain, c(0.0), c(0.0))));
+    let maxs: Vec<&NodeU> = gr.ugens.iter().filter(|x| x.name == "MaxLocalBufs").collect();
+    assert_eq!(maxs.len(), 1);
+    let mm = mk_map(&gr);
+    let pos_max = fetch(maxs[0].id, mm.us.clone());
+    for node in gr.ugens.iter().filter(|x| x.name == "LocalBuf") {
+        assert_eq!(node.inputs.len(), 3);
+        assert!(fetch(node.id, mm.us.clone()) > pos_max);
+    }
+    assert_eq!(gr.constants.iter().filter(|x| x.value == 2.0).count(), 1);
+}
+
+#[test]
+fn test_demand_rate() {
+    use ugens::{c, dseq, dwhite, demand};
+    let seq = dseq(&mk_mce(mk_ugenlist(&[&c(1.0), &c(2.0), &c(3.0)])), c(4.0));
+    assert_eq!(rate_of(&seq), Rate::RateDr);
+    let filt = mk_filter("LPF", mk_ugenlist(&[&seq, &c(440.0)]), 1);
+    assert_eq!(rate_of(&filt), Rate::RateKr);
+    assert_eq!(max_rate(vec![Rate::RateDr, Rate::RateAr], Rate::RateKr), Rate::RateAr);
+    let dem = demand(Rate::RateAr, c(1.0), c(0.0), &mk_mce(mk_ugenlist(&[&seq, &dwhite(c(0.0), c(1.0), c(8.0))])));
+    assert_eq!(mce_degree(&dem), 2);
+    assert_eq!(rate_of(&dem), Rate::RateAr);
+    assert_eq!(compare_ugen(&dwhite(c(0.0), c(1.0), c(8.0)), &dwhite(c(0.0), c(1.0), c(8.0))), false);
+}
+
+#[test]
+fn test_demand_operator() {
+    use ugens::{c, dseq, demand, out};
+    let seq = dseq(&mk_mce(mk_ugenlist(&[&c(1.0), &c(2.0), &c(3.0)])), c(4.0));
+    let scaled = seq * c(100.0);
+    assert_eq!(rate_of(&scaled), Rate::RateDr);
+    let dem = demand(Rate::RateKr, c(1.0), c(0.0), &scaled);
+    assert_eq!(rate_of(&dem), Rate::RateKr);
+    let data = synthdef("dem", &out(0, &dem));
+    //the rate byte follows the pstring name of each ugen
+    let name = b"\x0cBinaryOpUGen";
+    let pos = data.windows(name.len()).position(|x| x == &name[..]).unwrap();
+    assert_eq!(data[pos + name.len()], 3);
+    //filters never become demand rate, scsynth could not run them
+    let filt = mk_filter_mce("Out", mk_ugenlist(&[&c(0.0)]), &scaled, 0);
+    assert_eq!(rate_of(&filt), Rate::RateKr);
+}
+
+#[test]
+fn test_synthdef_hash() {
+    use ugens::{c, out};
+    let sine = |freq| out(0, &mk_oscillator(Rate::RateAr, "SinOsc", mk_ugenlist(&[&c(freq), &c(0.0)]), 1));
+    let def1 = SynthDef::anonymous(&sine(440.0));
+    assert_eq!(def1, SynthDef::anonymous(&sine(440.0)));
+    assert!(def1.name().starts_with("rsc3_"));
+    assert!(def1.name() != SynthDef::anonymous(&sine(220.0)).name());
+    assert_eq!(SynthDef::new("sine", &sine(440.0)).data(), &synthdef("sine", &sine(440.0))[..]);
+    //FNV-1a test vectors, names must not change with the compiler
+    assert_eq!(hash_bytes(b""), 0xcbf29ce484222325);
+    assert_eq!(hash_bytes(b"a"), 0xaf63dc4c8601ec8c);
+}
+
+#[test]
+fn test_synthdesc() {
+    use ugens::{c, control, out_to};
+    let line = mk_oscillator(Rate::RateKr, "Line", mk_ugenlist(&[&c(1.0), &c(0.0), &c(2.0), &c(2.0)]), 1);
+    let sine = mk_oscillator(Rate::RateAr, "SinOsc", mk_ugenlist(&[&control("freq", 440.0), &c(0.0)]), 1);
+    let gated = sine * line * control("gate", 1.0);
+    let stereo = mk_mce(mk_ugenlist(&[&gated, &gated]));
+    let desc = synthdesc("sine", &out_to(control("out", 0.0), &stereo));
+    let mut names: Vec<(String, f32)> = desc.controls.iter().map(|x| (x.name.clone(), x.default)).collect();
+    names.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(names, vec![("freq".to_string(), 440.0), ("gate".to_string(), 1.0), ("out".to_string(), 0.0)]);
+    assert_eq!(desc.outputs, vec![OutputDesc { rate: Rate::RateAr, bus: OutputBus::Control("out".to_string()), channels: 2 }]);
+    assert!(desc.has_gate);
+    assert!(desc.can_free_synth);
+    let desc = synthdesc("fixed", &out_to(c(2.0), &mk_oscillator(Rate::RateAr, "WhiteNoise", vec![], 1)));
+    assert_eq!(desc.outputs[0].bus, OutputBus::Fixed(2));
+    assert!(!desc.has_gate && !desc.can_free_synth);
+}
@@ -0,0 +1,1417 @@
+use osc::*;
+use alloc::{BlockAllocator, NodeEnds, NodeIdAllocator};
+use commands::*;
+use node::{NodeTree, Synth};
+use sc3::{Mce, Rate, SynthDef, Ugen};
+use ugens::out;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct ServerOptions {
+    pub addr: String,
+    pub local_addr: String,
+    pub tx_timeout: Duration,
+    pub rx_timeout: Duration,
+    //only a request, the server's /notify reply decides
+    pub client_id: i32,
+    pub max_logins: i32,
+    //These must match scsynth's -b, -a, -c, -o and -i. Buffers and buses are
+    //split evenly between max_logins clients, audio buses after the hardware ones.
+    pub num_buffers: i32,
+    pub num_audio_bus_channels: i32,
+    pub num_control_bus_channels: i32,
+    pub num_output_bus_channels: i32,
+    pub num_input_bus_channels: i32,
+    //Defs larger than this go through a file and /d_load when the server is
+    //local, as they may not fit in one UDP datagram. Ignored over TCP.
+    pub max_def_size: usize,
+    pub protocol: Protocol,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            addr: "127.0.0.1:57110".to_string(),
+            local_addr: "127.0.0.1:57111".to_string(),
+            tx_timeout: Duration::from_secs(5),
+            rx_timeout: Duration::from_secs(5),
+            client_id: 0,
+            max_logins: 1,
+            num_buffers: 1024,
+            num_audio_bus_channels: 1024,
+            num_control_bus_channels: 16384,
+            num_output_bus_channels: 8,
+            num_input_bus_channels: 8,
+            //the limit sclang uses
+            max_def_size: 65535 / 4,
+            protocol: Protocol::Udp,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ServerError {
+    Io(io::Error),
+    Osc(OscError),
+    //no reply to the named command within rx_timeout
+    Timeout(String),
+    //the server answered /fail for the named command
+    Failed { command: String, reason: String },
+    //the receive loop has stopped, no further replies can arrive
+    Disconnected,
+    //every id of the named kind in this client's range is taken
+    Exhausted(String),
+    //a def over max_def_size for a remote server reached over UDP
+    DefTooLarge { name: String, size: usize },
+    //a value command on the audio bus at this index, scsynth keeps values for control buses only
+    NotControlBus(i32),
+    //a command through a handle whose node has ended, its id may belong to another node by now
+    NodeEnded(i32),
+    //freeing a bus this client did not allocate, for example one from Bus::from_index
+    NotAllocated(i32),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::Io(err) => write!(f, "server i/o error: {}", err),
+            ServerError::Osc(err) => write!(f, "server sent a bad packet: {}", err),
+            ServerError::Timeout(cmd) => write!(f, "no reply to {} from the server", cmd),
+            ServerError::Failed { command, reason } => write!(f, "{} failed: {}", command, reason),
+            ServerError::Disconnected => write!(f, "server receive loop has stopped"),
+            ServerError::Exhausted(kind) => write!(f, "no free {} left", kind),
+            ServerError::DefTooLarge { name, size } => {
+                write!(f, "synthdef {} is {} bytes, too large for UDP to a remote server", name, size)
+            }
+            ServerError::NotControlBus(index) => write!(f, "bus {} is not a control bus", index),
+            ServerError::NodeEnded(id) => write!(f, "node {} has ended", id),
+            ServerError::NotAllocated(index) => write!(f, "bus {} was not allocated by this client", index),
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(err: io::Error) -> ServerError {
+        ServerError::Io(err)
+    }
+}
+
+impl From<OscError> for ServerError {
+    fn from(err: OscError) -> ServerError {
+        ServerError::Osc(err)
+    }
+}
+
+//A reply some caller is blocked on, taken by the first message it matches
+struct Waiter {
+    id: usize,
+    matcher: Box<dyn Fn(&Message) -> bool + Send>,
+    tx: Sender<Message>,
+}
+
+type WaiterList = Arc<Mutex<Vec<Waiter>>>;
+
+//set with on_error and on_unhandled, called on the receive thread
+type Callback<T> = Arc<Mutex<Option<Box<dyn Fn(&T) + Send>>>>;
+
+fn call<T>(callback: &Callback<T>, arg: &T) {
+    if let Some(ref handler) = *callback.lock().unwrap() {
+        handler(arg);
+    }
+}
+
+//What expect_reply returns for wait_reply. Dropping it, after a timeout or
+//when the request could not be sent, takes its waiter off the list.
+pub struct ReplyReceiver {
+    id: usize,
+    rx: Receiver<Message>,
+    waiters: WaiterList,
+}
+
+impl Drop for ReplyReceiver {
+    fn drop(&mut self) {
+        let id = self.id;
+        self.waiters.lock().unwrap().retain(|x| x.id != id);
+    }
+}
+
+//One transport and whether its receive loop still runs. reconnect replaces it
+//once a TCP server has closed the old one.
+struct Connection {
+    transport: Arc<dyn Transport>,
+    open: Arc<AtomicBool>,
+}
+
+struct ServerInner {
+    options: ServerOptions,
+    connection: Mutex<Connection>,
+    dispatcher: Arc<OscDispatcher>,
+    waiters: WaiterList,
+    errors: Callback<ServerError>,
+    unhandled: Callback<Message>,
+    running: Arc<AtomicBool>,
+    next_sync_id: AtomicI32,
+    next_waiter_id: AtomicUsize,
+    node_ids: Arc<Mutex<NodeIdAllocator>>,
+    node_ends: Arc<Mutex<NodeEnds>>,
+    node_tree: Arc<Mutex<NodeTree>>,
+    buffers: Mutex<BlockAllocator>,
+    audio_buses: Mutex<BlockAllocator>,
+    control_buses: Mutex<BlockAllocator>,
+    status: Arc<Mutex<Option<StatusInfo>>>,
+    //every def the server confirmed loading, in order, for recover
+    synthdefs: Mutex<Vec<SynthDef>>,
+    //defs on their way to the server, oldest first, until the /synced after them
+    pending_defs: Mutex<Vec<PendingDef>>,
+    //set once notify succeeds, from then on a /notify tells whether the server still knows us
+    notified: AtomicBool,
+    //reconnect replaced a closed TCP connection since the last recover
+    reconnected: AtomicBool,
+    //temp files written for /d_load that the server has not confirmed loading
+    def_files: Mutex<Vec<PathBuf>>,
+    //rsc3-{pid}-{n}, so two Servers in one process never share a temp file
+    def_file_prefix: String,
+}
+
+//numbers the Servers of this process for their temp file names
+static NEXT_SERVER: AtomicUsize = AtomicUsize::new(0);
+
+impl Drop for ServerInner {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        for path in self.def_files.lock().unwrap().drain(..) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+//A connection to one scsynth. Clones share the same socket, so a Server can be
+//handed to other threads.
+#[derive(Clone)]
+pub struct Server {
+    inner: Arc<ServerInner>,
+}
+
+impl Server {
+    pub fn new(options: ServerOptions) -> Result<Server, ServerError> {
+        let connection = Connection {
+            transport: connect_transport(&options)?,
+            open: Arc::new(AtomicBool::new(true)),
+        };
+        let transport = connection.transport.clone();
+        let open = connection.open.clone();
+        let dispatcher = Arc::new(OscDispatcher::new());
+        let node_ids = Arc::new(Mutex::new(NodeIdAllocator::new(options.client_id, options.max_logins)));
+        let node_ends = Arc::new(Mutex::new(NodeEnds::new()));
+        let end_ids = node_ids.clone();
+        let ends = node_ends.clone();
+        dispatcher.def("node_ids", "/n_end", move |message| {
+            if let Some(&Datum::Int(id)) = message.l_datum.get(0) {
+                //recorded before the id can be handed out again
+                ends.lock().unwrap().end(id);
+                end_ids.lock().unwrap().free(id);
+            }
+        });
+        let node_tree = Arc::new(Mutex::new(NodeTree::new()));
+        let tree = node_tree.clone();
+        dispatcher.def("node_tree", "/n_*", move |message| {
+            if let Ok(reply) = ServerReply::from_message(message) {
+                tree.lock().unwrap().update(&reply);
+            }
+        });
+        let (buffers, audio_buses, control_buses) = client_allocators(&options, options.client_id, options.max_logins);
+        let status = Arc::new(Mutex::new(None));
+        let latest = status.clone();
+        dispatcher.def("status", "/status.reply", move |message| {
+            if let Ok(ServerReply::Status(info)) = ServerReply::from_message(message) {
+                *latest.lock().unwrap() = Some(info);
+            }
+        });
+        let server = Server {
+            inner: Arc::new(ServerInner {
+                options: options,
+                connection: Mutex::new(connection),
+                dispatcher: dispatcher,
+                waiters: Arc::new(Mutex::new(Vec::new())),
+                errors: Arc::new(Mutex::new(None)),
+                unhandled: Arc::new(Mutex::new(None)),
+                running: Arc::new(AtomicBool::new(true)),
+                next_sync_id: AtomicI32::new(0),
+                next_waiter_id: AtomicUsize::new(0),
+                node_ids: node_ids,
+                node_ends: node_ends,
+                node_tree: node_tree,
+                buffers: Mutex::new(buffers),
+                audio_buses: Mutex::new(audio_buses),
+                control_buses: Mutex::new(control_buses),
+                status: status,
+                synthdefs: Mutex::new(Vec::new()),
+                pending_defs: Mutex::new(Vec::new()),
+                notified: AtomicBool::new(false),
+                reconnected: AtomicBool::new(false),
+                def_files: Mutex::new(Vec::new()),
+                def_file_prefix: format!("rsc3-{}-{}", process::id(), NEXT_SERVER.fetch_add(1, Ordering::SeqCst)),
+            }),
+        };
+        //Sends completions, so it needs the server; a Weak keeps the dispatcher
+        //from holding on to its own ServerInner. The /done of a def is left to
+        //the /synced after it.
+        let inner = Arc::downgrade(&server.inner);
+        server.inner.dispatcher.def("synthdefs", "/{done,fail,synced}", move |message| {
+            if let Some(inner) = inner.upgrade() {
+                Server { inner: inner }.def_reply(message);
+            }
+        });
+        server.spawn_receiver(transport, open);
+        Ok(server)
+    }
+
+    fn spawn_receiver(&self, transport: Arc<dyn Transport>, open: Arc<AtomicBool>) {
+        let dispatcher = self.inner.dispatcher.clone();
+        let waiters = self.inner.waiters.clone();
+        let errors = self.inner.errors.clone();
+        let unhandled = self.inner.unhandled.clone();
+        let running = self.inner.running.clone();
+        spawn(move || receive_loop(transport, dispatcher, waiters, errors, unhandled, running, open));
+    }
+
+    //Opens a new connection once a TCP server has closed the current one. UDP
+    //has no connection to lose, so there it does nothing.
+    pub fn reconnect(&self) -> Result<(), ServerError> {
+        let mut connection = self.inner.connection.lock().unwrap();
+        if connection.open.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        *connection = Connection {
+            transport: connect_transport(&self.inner.options)?,
+            open: Arc::new(AtomicBool::new(true)),
+        };
+        self.spawn_receiver(connection.transport.clone(), connection.open.clone());
+        self.inner.reconnected.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn options(&self) -> &ServerOptions {
+        &self.inner.options
+    }
+
+    pub fn client_id(&self) -> i32 {
+        self.inner.node_ids.lock().unwrap().client_id()
+    }
+
+    //a node id from this client's range, given back when the node's /n_end arrives
+    pub fn next_node_id(&self) -> i32 {
+        self.inner.node_ids.lock().unwrap().alloc()
+    }
+
+    //time on the clock of node ends, a handle made now keeps it for node_ended
+    pub fn node_time(&self) -> u64 {
+        self.inner.node_ends.lock().unwrap().now()
+    }
+
+    //whether the node with this id ended after time, or the server restarted since
+    pub fn node_ended(&self, id: i32, time: u64) -> bool {
+        self.inner.node_ends.lock().unwrap().has_ended(id, time)
+    }
+
+    pub fn default_group(&self) -> i32 {
+        self.inner.node_ids.lock().unwrap().default_group()
+    }
+
+    //what the server reported running so far, see NodeTree
+    pub fn node_tree(&self) -> NodeTree {
+        self.inner.node_tree.lock().unwrap().clone()
+    }
+
+    //Asks scsynth for everything below a group, control values included when
+    //controls is set
+    pub fn query_tree(&self, group: i32, controls: bool) -> Result<QueryNode, ServerError> {
+        let rx = self.expect_reply(move |reply| {
+            reply.name == "/g_queryTree.reply" && reply.l_datum.get(1) == Some(&Datum::Int(group))
+        });
+        self.send_command(&ServerCommand::GQueryTree { groups: vec![(group, controls)] })?;
+        let reply = self.wait_reply(&rx, "/g_queryTree")?;
+        match ServerReply::from_message(&reply)? {
+            ServerReply::QueryTree(tree) => Ok(tree),
+            _ => Err(ServerError::Osc(OscError::UnexpectedArguments(reply.name))),
+        }
+    }
+
+    //prints the whole node tree, like s.queryAllNodes in sclang
+    pub fn query_all_nodes(&self, controls: bool) -> Result<(), ServerError> {
+        print!("{}", self.query_tree(0, controls)?);
+        Ok(())
+    }
+
+    //count consecutive buffer numbers from this client's range
+    pub fn alloc_buffers(&self, count: i32) -> Result<i32, ServerError> {
+        match self.inner.buffers.lock().unwrap().alloc(count) {
+            Some(bufnum) => Ok(bufnum),
+            None => Err(ServerError::Exhausted("buffer numbers".to_string())),
+        }
+    }
+
+    pub fn free_buffers(&self, bufnum: i32) {
+        self.inner.buffers.lock().unwrap().free(bufnum);
+    }
+
+    fn buses(&self, rate: Rate) -> &Mutex<BlockAllocator> {
+        match rate {
+            Rate::RateAr => &self.inner.audio_buses,
+            _ => &self.inner.control_buses,
+        }
+    }
+
+    //count consecutive audio or control buses from this client's range
+    pub fn alloc_buses(&self, rate: Rate, count: i32) -> Result<i32, ServerError> {
+        match self.buses(rate).lock().unwrap().alloc(count) {
+            Some(index) => Ok(index),
+            None if rate == Rate::RateAr => Err(ServerError::Exhausted("audio buses".to_string())),
+            None => Err(ServerError::Exhausted("control buses".to_string())),
+        }
+    }
+
+    //false if no buses were allocated at index
+    pub fn free_buses(&self, rate: Rate, index: i32) -> bool {
+        self.buses(rate).lock().unwrap().free(index)
+    }
+
+    //the last /status.reply seen, from status_info or a StatusWatch
+    pub fn status(&self) -> Option<StatusInfo> {
+        self.inner.status.lock().unwrap().clone()
+    }
+
+    pub fn status_info(&self) -> Result<StatusInfo, ServerError> {
+        let rx = self.expect_reply(|reply| reply.name == "/status.reply");
+        self.send_command(&ServerCommand::Status)?;
+        let reply = self.wait_reply(&rx, "/status")?;
+        match ServerReply::from_message(&reply)? {
+            ServerReply::Status(info) => Ok(info),
+            _ => Err(ServerError::Osc(OscError::UnexpectedArguments(reply.name))),
+        }
+    }
+
+    //Sends a def and waits until the server has loaded it. The def is then
+    //remembered, so recover can load it again. A def with the same name and
+    //content as one loaded before, or on its way, is not sent again.
+    pub fn send_synthdef(&self, def: &SynthDef) -> Result<DefTransfer, ServerError> {
+        if self.has_synthdef(def) {
+            return Ok(DefTransfer::Cached);
+        }
+        let (tx, rx) = channel();
+        let (sync_id, transfer) = self.queue_def(def, None, Some(tx))?;
+        match rx.recv_timeout(self.inner.options.rx_timeout) {
+            Ok(result) => result.map(|_| transfer),
+            Err(RecvTimeoutError::Timeout) => {
+                self.drop_pending_defs(|x| x.sync_id == sync_id);
+                Err(ServerError::Timeout("/sync".to_string()))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(ServerError::Disconnected),
+        }
+    }
+
+    //like send_synthdef, but returns at once and the server runs completion
+    //as soon as the def is loaded
+    pub fn send_synthdef_then(&self, def: &SynthDef, completion: Packet) -> Result<DefTransfer, ServerError> {
+        if self.has_synthdef(def) {
+            self.send_packet(&completion)?;
+            return Ok(DefTransfer::Cached);
+        }
+        let (_, transfer) = self.queue_def(def, Some(completion), None)?;
+        Ok(transfer)
+    }
+
+    //Sends the def followed by a /sync of its own, whose /synced tells def_reply
+    //that the server is done with it. A def already on its way is not sent
+    //again, completion and waiter then go with the one sent before.
+    fn queue_def(&self, def: &SynthDef, completion: Option<Packet>, waiter: Option<DefWaiter>)
+                 -> Result<(i32, DefTransfer), ServerError> {
+        let mut pending = self.inner.pending_defs.lock().unwrap();
+        let timeout = self.inner.options.rx_timeout;
+        self.drop_defs(&mut pending, |x| x.sent.elapsed() >= timeout);
+        if let Some(entry) = pending.iter_mut().find(|x| x.def.name() == def.name() && x.def.hash() == def.hash()) {
+            entry.completions.extend(completion);
+            entry.waiters.extend(waiter);
+            return Ok((entry.sync_id, entry.transfer.clone()));
+        }
+        let (command, transfer) = self.def_command(def)?;
+        let sync_id = self.inner.next_sync_id.fetch_add(1, Ordering::SeqCst);
+        pending.push(PendingDef {
+            sync_id: sync_id,
+            command: command.address().to_string(),
+            def: def.clone(),
+            transfer: transfer.clone(),
+            sent: Instant::now(),
+            failed: None,
+            completions: Vec::new(),
+            waiters: waiter.into_iter().collect(),
+        });
+        //unlocked while sending, the receive thread needs it for replies
+        drop(pending);
+        let sent = match completion {
+            Some(completion) => self.send_command_then(&command, completion),
+            None => self.send_message(command.to_message()),
+        };
+        if let Err(err) = sent.and_then(|_| self.send_command(&ServerCommand::Sync { id: sync_id })) {
+            self.drop_pending_defs(|x| x.sync_id == sync_id);
+            return Err(err);
+        }
+        Ok((sync_id, transfer))
+    }
+
+    //Handler for replies to def commands. scsynth completes async commands in
+    //order, so a /fail is for the oldest def sent with that command, and the
+    //def is settled by the /synced that follows it.
+    fn def_reply(&self, message: &Message) {
+        let mut pending = self.inner.pending_defs.lock().unwrap();
+        match (message.name.as_str(), message.l_datum.get(0)) {
+            ("/fail", Some(&Datum::Str(ref command))) => {
+                if let Some(entry) = pending.iter_mut().find(|x| x.failed.is_none() && x.command == *command) {
+                    entry.failed = Some(match message.l_datum.get(1) {
+                        Some(&Datum::Str(ref reason)) => reason.clone(),
+                        _ => String::new(),
+                    });
+                }
+            }
+            ("/synced", Some(&Datum::Int(id))) => {
+                if let Some(pos) = pending.iter().position(|x| x.sync_id == id) {
+                    let entry = pending.remove(pos);
+                    drop(pending);
+                    self.finish_def(entry);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn finish_def(&self, entry: PendingDef) {
+        //the server has read the file, or given up on it
+        if let DefTransfer::Load(ref path) = entry.transfer {
+            remove_def_file(&self.inner.def_files, path);
+        }
+        match entry.failed {
+            Some(reason) => {
+                for waiter in entry.waiters {
+                    let _ = waiter.send(Err(ServerError::Failed {
+                        command: entry.command.clone(),
+                        reason: reason.clone(),
+                    }));
+                }
+            }
+            None => {
+                remember_synthdef(&mut self.inner.synthdefs.lock().unwrap(), entry.def);
+                for completion in &entry.completions {
+                    if let Err(err) = self.send_packet(completion) {
+                        call(&self.inner.errors, &err);
+                    }
+                }
+                for waiter in entry.waiters {
+                    let _ = waiter.send(Ok(()));
+                }
+            }
+        }
+    }
+
+    fn drop_pending_defs<F: Fn(&PendingDef) -> bool>(&self, matches: F) {
+        self.drop_defs(&mut self.inner.pending_defs.lock().unwrap(), matches);
+    }
+
+    //Forgets pending defs, for sends that failed, defs whose /synced got lost
+    //and after a restart. send_synthdef calls waiting for them get Disconnected.
+    fn drop_defs<F: Fn(&PendingDef) -> bool>(&self, pending: &mut Vec<PendingDef>, matches: F) {
+        let (dropped, kept): (Vec<PendingDef>, Vec<PendingDef>) = pending.drain(..).partition(|x| matches(x));
+        *pending = kept;
+        for entry in dropped {
+            if let DefTransfer::Load(ref path) = entry.transfer {
+                remove_def_file(&self.inner.def_files, path);
+            }
+        }
+    }
+
+    pub fn has_synthdef(&self, def: &SynthDef) -> bool {
+        self.inner.synthdefs.lock().unwrap().iter().any(|x| x.name() == def.name() && x.hash() == def.hash())
+    }
+
+    //names of the defs this server has loaded
+    pub fn synthdef_names(&self) -> Vec<String> {
+        self.inner.synthdefs.lock().unwrap().iter().map(|x| x.name().to_string()).collect()
+    }
+
+    //a /d_recv, or a /d_load of a temp file for a large def sent to a local server
+    fn def_command(&self, def: &SynthDef) -> Result<(ServerCommand, DefTransfer), ServerError> {
+        let options = &self.inner.options;
+        let data = def.data();
+        if data.len() <= options.max_def_size || options.protocol != Protocol::Udp {
+            return Ok((ServerCommand::DRecv { data: data.to_vec() }, DefTransfer::Recv));
+        }
+        if !is_local(&options.addr) {
+            return Err(ServerError::DefTooLarge {
+                name: def.name().to_string(),
+                size: data.len(),
+            });
+        }
+        let file_name: String = def.name().chars().map(|c| if c.is_alphanumeric() { c } else { '_' }).collect();
+        //with the hash, two defs of one name on their way at once do not share a file
+        let path = env::temp_dir().join(format!("{}-{:016x}-{}.scsyndef", self.inner.def_file_prefix, def.hash(), file_name));
+        fs::write(&path, data)?;
+        let mut def_files = self.inner.def_files.lock().unwrap();
+        if !def_files.contains(&path) {
+            def_files.push(path.clone());
+        }
+        let command = ServerCommand::DLoad { path: path.to_string_lossy().into_owned() };
+        Ok((command, DefTransfer::Load(path)))
+    }
+
+    //Brings a restarted server back to where this client left it: notifications
+    //on, the default group created and every def from send_synthdef loaded. The
+    //nodes, buffers and buses that existed are gone, so their ids are handed out again.
+    pub fn recover(&self) -> Result<(), ServerError> {
+        self.reconnect()?;
+        self.inner.reconnected.store(false, Ordering::SeqCst);
+        *self.inner.node_tree.lock().unwrap() = NodeTree::new();
+        self.inner.node_ends.lock().unwrap().end_all();
+        let (client_id, max_logins) = self.client_range();
+        self.reset_allocators(client_id, max_logins);
+        self.notify()?;
+        let group = self.default_group();
+        self.send_command(&ServerCommand::GNew { groups: vec![(group, AddAction::AddToHead, 0)] })?;
+        //whatever was on its way went to the old server process
+        self.drop_pending_defs(|_| true);
+        let synthdefs = self.inner.synthdefs.lock().unwrap().clone();
+        for def in synthdefs {
+            self.queue_def(&def, None, None)?;
+        }
+        self.sync()
+    }
+
+    //Polls /status every interval until the returned StatusWatch is dropped,
+    //see ServerEvent for what the handler is told.
+    pub fn watch<F>(&self, interval: Duration, handler: F) -> StatusWatch
+    where
+        F: Fn(ServerEvent) + Send + 'static,
+    {
+        let running = Arc::new(AtomicBool::new(true));
+        let watch_running = running.clone();
+        let inner = Arc::downgrade(&self.inner);
+        spawn(move || watch_loop(inner, interval, handler, watch_running));
+        StatusWatch { running: running }
+    }
+
+    //Registers for notifications and takes the client id and maxLogins from
+    //the /done /notify reply, moving node ids into that client's range. A
+    //server that has this client registered already answers /fail, which is
+    //not an error here.
+    pub fn notify(&self) -> Result<(), ServerError> {
+        self.register().map(|_| ())
+    }
+
+    //notify, returning whether the server took this client as a new one
+    fn register(&self) -> Result<bool, ServerError> {
+        let client_id = self.inner.options.client_id;
+        let command = ServerCommand::Notify {
+            on: true,
+            client_id: if client_id > 0 { Some(client_id) } else { None },
+        };
+        let reply = match self.send_async(command.to_message()) {
+            Ok(reply) => reply,
+            Err(ServerError::Failed { ref reason, .. }) if reason.contains("already registered") => {
+                self.inner.notified.store(true, Ordering::SeqCst);
+                return Ok(false);
+            }
+            Err(err) => return Err(err),
+        };
+        if let ServerReply::Done { args, .. } = ServerReply::from_message(&reply)? {
+            let current = self.client_range();
+            let client_id = match args.get(0) {
+                Some(&Datum::Int(id)) => id,
+                _ => current.0,
+            };
+            let max_logins = match args.get(1) {
+                Some(&Datum::Int(max)) => max,
+                _ => current.1,
+            };
+            if (client_id, max_logins) != current {
+                self.reset_allocators(client_id, max_logins);
+            }
+        }
+        self.inner.notified.store(true, Ordering::SeqCst);
+        Ok(true)
+    }
+
+    //Whether a server that answers again after being lost has forgotten this
+    //client: its TCP connection had to be opened again, or it takes /notify as
+    //a new registration. A missed UDP reply or a d_free proves nothing, and
+    //without an earlier notify there is nothing to ask.
+    fn was_restarted(&self) -> Result<bool, ServerError> {
+        if self.inner.reconnected.load(Ordering::SeqCst) {
+            return Ok(true);
+        }
+        if !self.inner.notified.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        self.register()
+    }
+
+    //client id and maxLogins the allocators were set up for
+    fn client_range(&self) -> (i32, i32) {
+        let node_ids = self.inner.node_ids.lock().unwrap();
+        (node_ids.client_id(), node_ids.max_logins())
+    }
+
+    fn reset_allocators(&self, client_id: i32, max_logins: i32) {
+        let (buffers, audio_buses, control_buses) = client_allocators(&self.inner.options, client_id, max_logins);
+        *self.inner.node_ids.lock().unwrap() = NodeIdAllocator::new(client_id, max_logins);
+        *self.inner.buffers.lock().unwrap() = buffers;
+        *self.inner.audio_buses.lock().unwrap() = audio_buses;
+        *self.inner.control_buses.lock().unwrap() = control_buses;
+    }
+
+    //Called on the receive thread for receive errors and packets that do not
+    //decode. Without a handler they are ignored and receiving goes on.
+    pub fn on_error<F>(&self, handler: F)
+    where
+        F: Fn(&ServerError) + Send + 'static,
+    {
+        *self.inner.errors.lock().unwrap() = Some(Box::new(handler));
+    }
+
+    //called for messages that no dispatcher handler and no waiter took, such as late replies
+    pub fn on_unhandled<F>(&self, handler: F)
+    where
+        F: Fn(&Message) + Send + 'static,
+    {
+        *self.inner.unhandled.lock().unwrap() = Some(Box::new(handler));
+    }
+
+    //Handlers registered here run on the receive thread for every message from
+    //the server, including replies also claimed by send_async or sync.
+    pub fn dispatcher(&self) -> &OscDispatcher {
+        &self.inner.dispatcher
+    }
+
+    pub fn send_packet(&self, packet: &Packet) -> Result<(), ServerError> {
+        let bpkt = encode_packet(packet);
+        let transport = self.inner.connection.lock().unwrap().transport.clone();
+        transport.send(&bpkt)?;
+        Ok(())
+    }
+
+    pub fn send_message(&self, message: Message) -> Result<(), ServerError> {
+        self.send_packet(&Packet::Message(message))
+    }
+
+    pub fn send_bundle(&self, bundle: Bundle) -> Result<(), ServerError> {
+        self.send_packet(&Packet::Bundle(bundle))
+    }
+
+    //Registers interest in a reply. Call it before sending the request so a fast
+    //reply cannot slip past.
+    pub fn expect_reply<F>(&self, matcher: F) -> ReplyReceiver
+    where
+        F: Fn(&Message) -> bool + Send + 'static,
+    {
+        let (tx, rx) = channel();
+        let id = self.inner.next_waiter_id.fetch_add(1, Ordering::SeqCst);
+        let waiter = Waiter {
+            id: id,
+            matcher: Box::new(matcher),
+            tx: tx,
+        };
+        self.inner.waiters.lock().unwrap().push(waiter);
+        ReplyReceiver {
+            id: id,
+            rx: rx,
+            waiters: self.inner.waiters.clone(),
+        }
+    }
+
+    pub fn wait_reply(&self, reply: &ReplyReceiver, command: &str) -> Result<Message, ServerError> {
+        match reply.rx.recv_timeout(self.inner.options.rx_timeout) {
+            Ok(message) => Ok(message),
+            Err(RecvTimeoutError::Timeout) => Err(ServerError::Timeout(command.to_string())),
+            Err(RecvTimeoutError::Disconnected) => Err(ServerError::Disconnected),
+        }
+    }
+
+    //Sends an asynchronous command and waits for its /done, turning /fail into an error
+    pub fn send_async(&self, message: Message) -> Result<Message, ServerError> {
+        let command = message.name.clone();
+        let cmd = command.clone();
+        let rx = self.expect_reply(move |reply| {
+            (reply.name == "/done" || reply.name == "/fail") &&
+            reply.l_datum.get(0) == Some(&Datum::Str(cmd.clone()))
+        });
+        self.send_message(message)?;
+        let reply = self.wait_reply(&rx, &command)?;
+        match ServerReply::from_message(&reply)? {
+            ServerReply::Fail { error, .. } => Err(ServerError::Failed {
+                command: command,
+                reason: error,
+            }),
+            _ => Ok(reply),
+        }
+    }
+
+    //Sends a command, waiting for its /done when the command is asynchronous
+    pub fn send_command(&self, command: &ServerCommand) -> Result<(), ServerError> {
+        if command.is_async() {
+            self.send_async(command.to_message())?;
+            return Ok(());
+        }
+        self.send_message(command.to_message())
+    }
+
+    //Sends a command with a packet for scsynth to run once the command is done,
+    //without waiting for /done; a /fail only shows up on the receive thread.
+    //Commands that take no completion message are sent as usual and followed
+    //by the packet.
+    pub fn send_command_then(&self, command: &ServerCommand, completion: Packet) -> Result<(), ServerError> {
+        if command.takes_completion() {
+            return self.send_message(command.to_message_with(&completion));
+        }
+        self.send_command(command)?;
+        self.send_packet(&completion)
+    }
+
+    //Waits until every command sent before it has been completed by the server
+    pub fn sync(&self) -> Result<(), ServerError> {
+        let id = self.inner.next_sync_id.fetch_add(1, Ordering::SeqCst);
+        let rx = self.expect_reply(move |reply| {
+            reply.name == "/synced" && reply.l_datum.get(0) == Some(&Datum::Int(id))
+        });
+        self.send_command(&ServerCommand::Sync { id: id })?;
+        self.wait_reply(&rx, "/sync")?;
+        Ok(())
+    }
+}
+
+//buffer, audio bus and control bus allocators for one client's share
+fn client_allocators(options: &ServerOptions, client_id: i32, max_logins: i32)
+                     -> (BlockAllocator, BlockAllocator, BlockAllocator) {
+    let first_private_bus = options.num_output_bus_channels + options.num_input_bus_channels;
+    (BlockAllocator::client_range(0, options.num_buffers, client_id, max_logins),
+     BlockAllocator::client_range(first_private_bus, options.num_audio_bus_channels, client_id, max_logins),
+     BlockAllocator::client_range(0, options.num_control_bus_channels, client_id, max_logins))
+}
+
+//A def sent to the server, until the /synced of the /sync sent right after it
+struct PendingDef {
+    sync_id: i32,
+    //address of the command that carried it, to match /fail replies
+    command: String,
+    def: SynthDef,
+    transfer: DefTransfer,
+    sent: Instant,
+    //the reason of a /fail for it
+    failed: Option<String>,
+    //completions of later send_synthdef_then calls for the same def, sent once it is loaded
+    completions: Vec<Packet>,
+    waiters: Vec<DefWaiter>,
+}
+
+//how a send_synthdef call learns whether its def was loaded
+type DefWaiter = Sender<Result<(), ServerError>>;
+
+fn remove_def_file(def_files: &Mutex<Vec<PathBuf>>, path: &Path) {
+    def_files.lock().unwrap().retain(|x| x != path);
+    let _ = fs::remove_file(path);
+}
+
+//a def loaded again under the same name replaces the old one
+fn remember_synthdef(synthdefs: &mut Vec<SynthDef>, def: SynthDef) {
+    synthdefs.retain(|x| x.name() != def.name());
+    synthdefs.push(def);
+}
+
+//How send_synthdef got a def to the server
+#[derive(Clone, PartialEq, Debug)]
+pub enum DefTransfer {
+    //the server already had it, nothing was sent
+    Cached,
+    //inline in /d_recv
+    Recv,
+    //Written to this temp file and loaded with /d_load. The file is removed once
+    //the server answers the /d_load, or when the Server is dropped.
+    Load(PathBuf),
+}
+
+fn is_local(addr: &str) -> bool {
+    match addr.to_socket_addrs() {
+        Ok(mut addrs) => addrs.any(|x| x.ip().is_loopback()),
+        Err(_) => false,
+    }
+}
+
+fn connect_transport(options: &ServerOptions) -> Result<Arc<dyn Transport>, ServerError> {
+    let transport: Arc<dyn Transport> = match options.protocol {
+        Protocol::Udp => Arc::new(UdpTransport::connect(&options.local_addr, &options.addr,
+                                                       POLL_INTERVAL, options.tx_timeout)?),
+        Protocol::Tcp(framing) => Arc::new(TcpTransport::connect(&options.addr, framing,
+                                                                POLL_INTERVAL, options.tx_timeout)?),
+    };
+    Ok(transport)
+}
+
+//What a StatusWatch reports
+#[derive(Debug)]
+pub enum ServerEvent {
+    Status(StatusInfo),
+    //no /status.reply within rx_timeout, or the request could not be sent
+    Lost,
+    //The server answers again after being lost and has forgotten this client,
+    //see Server::was_restarted. recover has already been run.
+    Restarted,
+    //recover, or asking the server whether it restarted, went wrong
+    RecoverFailed(ServerError),
+}
+
+//stops the status polling when dropped
+pub struct StatusWatch {
+    running: Arc<AtomicBool>,
+}
+
+impl StatusWatch {
+    pub fn stop(self) {}
+}
+
+impl Drop for StatusWatch {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+//holds only a weak reference, so a watch does not keep the Server alive
+fn watch_loop<F>(inner: Weak<ServerInner>, interval: Duration, handler: F, running: Arc<AtomicBool>)
+where
+    F: Fn(ServerEvent),
+{
+    let mut lost = false;
+    loop {
+        sleep(interval);
+        if !running.load(Ordering::SeqCst) {
+            break;
+        }
+        let server = match inner.upgrade() {
+            Some(inner) => Server { inner: inner },
+            None => break,
+        };
+        match server.status_info() {
+            Ok(status) => {
+                if lost {
+                    lost = false;
+                    match server.was_restarted() {
+                        Ok(true) => match server.recover() {
+                            Ok(()) => handler(ServerEvent::Restarted),
+                            Err(err) => handler(ServerEvent::RecoverFailed(err)),
+                        },
+                        Ok(false) => {}
+                        Err(err) => handler(ServerEvent::RecoverFailed(err)),
+                    }
+                }
+                handler(ServerEvent::Status(status));
+            }
+            Err(_) => {
+                if !lost {
+                    lost = true;
+                    handler(ServerEvent::Lost);
+                }
+                //a TCP server that went away closed the connection, retry it
+                let _ = server.reconnect();
+            }
+        }
+    }
+}
+
+fn flatten_packet(packet: Packet, out: &mut Vec<Message>) {
+    match packet {
+        Packet::Message(message) => out.push(message),
+        Packet::Bundle(bundle) => {
+            for elem in bundle.packets {
+                flatten_packet(elem, out);
+            }
+        }
+    }
+}
+
+//hands a reply to the first waiter it matches, returns false if nobody wanted it
+fn route_reply(message: &Message, waiters: &WaiterList) -> bool {
+    let mut lst = waiters.lock().unwrap();
+    loop {
+        let pos = lst.iter().position(|x| (x.matcher)(message));
+        match pos {
+            Some(pos) => {
+                let waiter = lst.remove(pos);
+                //a caller that timed out has dropped its receiver, try the next one
+                if waiter.tx.send(message.clone()).is_ok() {
+                    return true;
+                }
+            }
+            None => return false,
+        }
+    }
+}
+
+fn receive_loop(transport: Arc<dyn Transport>, dispatcher: Arc<OscDispatcher>, waiters: WaiterList,
+                errors: Callback<ServerError>, unhandled: Callback<Message>,
+                running: Arc<AtomicBool>, open: Arc<AtomicBool>) {
+    while running.load(Ordering::SeqCst) {
+        let bpkt = match transport.receive() {
+            Ok(Some(bpkt)) => bpkt,
+            Ok(None) => continue,
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => {
+                call(&errors, &ServerError::Io(err));
+                continue;
+            }
+        };
+        match decode_packet(&bpkt) {
+            Ok(packet) => {
+                let mut messages = Vec::new();
+                flatten_packet(packet, &mut messages);
+                for message in messages {
+                    //handlers first, so state they keep is current when a waiter wakes up
+                    let handled = dispatcher.dispatch(&message);
+                    if !route_reply(&message, &waiters) && !handled {
+                        call(&unhandled, &message);
+                    }
+                }
+            }
+            Err(err) => call(&errors, &ServerError::Osc(err)),
+        }
+    }
+    open.store(false, Ordering::SeqCst);
+    //wakes up anyone still waiting with ServerError::Disconnected
+    waiters.lock().unwrap().clear();
+}
+
+pub fn sc_start(server: &Server) -> Result<(), ServerError> {
+    server.notify()?;
+    let group = server.default_group();
+    server.send_command(&ServerCommand::GNew { groups: vec![(group, AddAction::AddToHead, 0)] })?;
+    server.sync()
+}
+
+pub fn sc_stop(server: &Server) -> Result<(), ServerError> {
+    server.send_command(&ServerCommand::GDeepFree { ids: vec![server.default_group()] })?;
+    server.sync()
+}
+
+pub fn sc_play(server: &Server, ugen: &Ugen) -> Result<Synth, ServerError> {
+    play_synthdef(server, &SynthDef::anonymous(&out(0, ugen)))
+}
+
+//loads the def and starts a synth from it in one packet
+fn play_synthdef(server: &Server, def: &SynthDef) -> Result<Synth, ServerError> {
+    let synth = Synth::from_id(server, def.name(), server.next_node_id());
+    let s_new = synth.s_new(vec![], AddAction::AddToTail, server.default_group());
+    server.send_synthdef_then(def, Packet::Message(s_new.to_message()))?;
+    Ok(synth)
+}
+
+//like sc_play, but the synth starts at the given time on the server clock
+pub fn sc_play_at(server: &Server, ugen: &Ugen, time: TimeTag) -> Result<Synth, ServerError> {
+    let def = SynthDef::anonymous(&out(0, ugen));
+    server.send_synthdef(&def)?;
+    let synth = Synth::from_id(server, def.name(), server.next_node_id());
+    let s_new = synth.s_new(vec![], AddAction::AddToTail, server.default_group());
+    server.send_bundle(Bundle::new(time, vec![Packet::Message(s_new.to_message())]))?;
+    Ok(synth)
+}
+
+pub fn sc_play_vec(server: &Server, ugens: Vec<Ugen>) -> Result<Synth, ServerError> {
+    let mut ulist = Vec::new();
+    let s_ugen: Ugen;
+    /*
+    if ulist.len() == 2 {
+        s_ugen = Ugen::Mrg(Mrg{left: Box::new(ugens[0].clone()), right: Box::new(ugens[1].clone())});
+    }
+    else
+    */
+    {
+        for ugen in ugens {
+            ulist.push(Box::new(ugen));
+        }
+        s_ugen = Ugen::Mce(Mce{ugens: ulist});
+    }
+    play_synthdef(server, &SynthDef::anonymous(&out(0, &s_ugen)))
+}
+
+//What the fake scsynth does, changed by the tests while it runs, and what it got
+#[cfg(test)]
+pub struct FakeScsynth {
+    //silent while false, and like a restarted scsynth it forgets its clients
+    alive: AtomicBool,
+    registered: AtomicBool,
+    //status requests to leave unanswered, like dropped UDP replies
+    missed_status: AtomicI32,
+    synthdefs: AtomicI32,
+    //milliseconds each /d_recv takes, like a big def
+    recv_delay: AtomicI32,
+    //the samples of every buffer and the values of the control buses, one store each
+    samples: Mutex<Vec<f32>>,
+    buses: Mutex<Vec<f32>>,
+    received_tx: Mutex<Sender<Message>>,
+    received: Mutex<Receiver<Message>>,
+    //the socket and the client that wrote last, to send notifications to
+    client: Mutex<Option<(::std::net::UdpSocket, ::std::net::SocketAddr)>>,
+}
+
+#[cfg(test)]
+impl FakeScsynth {
+    pub fn new() -> Arc<FakeScsynth> {
+        let (tx, rx) = channel();
+        Arc::new(FakeScsynth {
+            alive: AtomicBool::new(true),
+            registered: AtomicBool::new(false),
+            missed_status: AtomicI32::new(0),
+            synthdefs: AtomicI32::new(1),
+            recv_delay: AtomicI32::new(0),
+            samples: Mutex::new(vec![0.0; 4096]),
+            buses: Mutex::new(vec![0.0; 4096]),
+            received_tx: Mutex::new(tx),
+            received: Mutex::new(rx),
+            client: Mutex::new(None),
+        })
+    }
+
+    //the next message the fake got from the client, in the order they came
+    pub fn next_message(&self) -> Message {
+        self.received.lock().unwrap().recv_timeout(Duration::from_secs(2)).unwrap()
+    }
+
+    //sends a message of its own, like /n_end when a synth is done
+    pub fn send_to_client(&self, message: &Message) {
+        let client = self.client.lock().unwrap();
+        let &(ref socket, addr) = client.as_ref().unwrap();
+        socket.send_to(&encode_message(message), addr).unwrap();
+    }
+}
+
+//writes index count values... into store, as /b_setn and /c_setn do
+#[cfg(test)]
+fn fake_setn(store: &Mutex<Vec<f32>>, l_datum: &[Datum]) {
+    if let (Some(&Datum::Int(index)), Some(&Datum::Int(_))) = (l_datum.get(0), l_datum.get(1)) {
+        let mut store = store.lock().unwrap();
+        for (i, x) in l_datum[2..].iter().enumerate() {
+            if let Datum::Float(x) = *x {
+                store[index as usize + i] = x;
+            }
+        }
+    }
+}
+
+//answers index count with index count values..., as /b_getn and /c_getn do
+#[cfg(test)]
+fn fake_getn(store: &Mutex<Vec<f32>>, l_datum: &[Datum]) -> Option<Vec<Datum>> {
+    match (l_datum.get(0), l_datum.get(1)) {
+        (Some(&Datum::Int(index)), Some(&Datum::Int(count))) => {
+            let store = store.lock().unwrap();
+            let values = store[index as usize..(index + count) as usize].iter().map(|&x| Datum::Float(x));
+            Some(l_datum[0..2].iter().cloned().chain(values).collect())
+        }
+        _ => None,
+    }
+}
+
+//Answers like scsynth would: /notify with /done, or /fail once registered,
+//d_recv of an empty def with /fail, other defs and d_load of a file that is there with /done, sync
+//with /synced and status with a status.reply, while d_free lowers the def count.
+//b_setn and c_setn are kept and b_getn and c_getn answered from them.
+//Every message is also passed on to next_message.
+#[cfg(test)]
+pub fn fake_scsynth(fake: &Arc<FakeScsynth>) -> String {
+    let socket = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap().to_string();
+    let fake = fake.clone();
+    spawn(move || {
+        let mut buf = vec![0; 65536];
+        loop {
+            let (amt, src) = socket.recv_from(&mut buf).unwrap();
+            let message = decode_message(&buf[0..amt]).unwrap();
+            *fake.client.lock().unwrap() = Some((socket.try_clone().unwrap(), src));
+            let _ = fake.received_tx.lock().unwrap().send(message.clone());
+            if !fake.alive.load(Ordering::SeqCst) {
+                fake.registered.store(false, Ordering::SeqCst);
+                continue;
+            }
+            let reply = match message.name.as_str() {
+                "/notify" if fake.registered.swap(true, Ordering::SeqCst) => Message::new("/fail", vec![
+                    Datum::Str("/notify".to_string()), Datum::Str("notify: already registered".to_string()), Datum::Int(2),
+                ]),
+                "/notify" => Message::new("/done", vec![Datum::Str("/notify".to_string()), Datum::Int(2), Datum::Int(4)]),
+                "/d_recv" if message.l_datum.get(0) == Some(&Datum::Blob(vec![])) => Message::new("/fail", vec![
+                    Datum::Str("/d_recv".to_string()), Datum::Str("bad def".to_string()),
+                ]),
+                "/d_recv" => {
+                    sleep(Duration::from_millis(fake.recv_delay.load(Ordering::SeqCst) as u64));
+                    Message::new("/done", vec![Datum::Str("/d_recv".to_string())])
+                }
+                "/d_load" => match message.l_datum.get(0) {
+                    Some(&Datum::Str(ref path)) if fs::metadata(path).is_ok() => {
+                        Message::new("/done", vec![Datum::Str("/d_load".to_string())])
+                    }
+                    _ => Message::new("/fail", vec![
+                        Datum::Str("/d_load".to_string()), Datum::Str("file not found".to_string()),
+                    ]),
+                },
+                "/d_free" => {
+                    fake.synthdefs.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+                "/b_setn" => {
+                    fake_setn(&fake.samples, &message.l_datum[1..]);
+                    continue;
+                }
+                "/c_setn" => {
+                    fake_setn(&fake.buses, &message.l_datum);
+                    continue;
+                }
+                "/b_getn" => match fake_getn(&fake.samples, &message.l_datum[1..]) {
+                    Some(values) => Message::new("/b_setn", message.l_datum[0..1].iter().cloned().chain(values).collect()),
+                    None => continue,
+                },
+                "/c_getn" => match fake_getn(&fake.buses, &message.l_datum) {
+                    Some(values) => Message::new("/c_setn", values),
+                    None => continue,
+                },
+                "/sync" => Message::new("/synced", message.l_datum.clone()),
+                "/status" if fake.missed_status.load(Ordering::SeqCst) > 0 => {
+                    fake.missed_status.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+                "/status" => Message::new("/status.reply", vec![
+                    Datum::Int(1), Datum::Int(0), Datum::Int(0), Datum::Int(2),
+                    Datum::Int(fake.synthdefs.load(Ordering::SeqCst)),
+                    Datum::Float(0.5), Datum::Float(1.0), Datum::Double(44100.0), Datum::Double(44100.0),
+                ]),
+                _ => continue,
+            };
+            //an unrelated notification first, it must not be taken for the reply
+            socket.send_to(&encode_message(&Message::new("/n_go", vec![Datum::Int(1000)])), src).unwrap();
+            socket.send_to(&encode_message(&reply), src).unwrap();
+        }
+    });
+    addr
+}
+
+//options for a client of a fake scsynth started on the spot
+#[cfg(test)]
+pub fn fake_options(fake: &Arc<FakeScsynth>) -> ServerOptions {
+    ServerOptions {
+        addr: fake_scsynth(fake),
+        local_addr: "127.0.0.1:0".to_string(),
+        ..ServerOptions::default()
+    }
+}
+
+#[test]
+fn test_async_replies() {
+    let options = ServerOptions {
+        rx_timeout: Duration::from_secs(1),
+        ..fake_options(&FakeScsynth::new())
+    };
+    let server = Server::new(options).unwrap();
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    server.dispatcher().add("/n_go", move |message| { tx.lock().unwrap().send(message.clone()).unwrap(); });
+    server.notify().unwrap();
+    assert_eq!(server.client_id(), 2);
+    assert_eq!(server.default_group(), 2 * (1 << 29) + 1);
+    match server.send_async(Message::new("/notify", vec![Datum::Int(1)])) {
+        Err(ServerError::Failed { reason, .. }) => assert_eq!(reason, "notify: already registered"),
+        other => panic!("expected /fail, got {:?}", other),
+    }
+    server.notify().unwrap();
+    assert_eq!(server.client_id(), 2);
+    server.sync().unwrap();
+    match server.send_async(Message::new("/d_recv", vec![Datum::Blob(vec![])])) {
+        Err(ServerError::Failed { command, reason }) => {
+            assert_eq!(command, "/d_recv");
+            assert_eq!(reason, "bad def");
+        }
+        other => panic!("expected /fail, got {:?}", other),
+    }
+    match server.send_async(Message::new("/b_query", vec![])) {
+        Err(ServerError::Timeout(command)) => assert_eq!(command, "/b_query"),
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+    //the timed out waiter does not stay behind
+    assert_eq!(server.inner.waiters.lock().unwrap().len(), 0);
+    let (tx, unhandled) = channel();
+    server.on_unhandled(move |message| tx.send(message.clone()).unwrap());
+    server.send_command(&ServerCommand::CGetn { ranges: vec![(5, 1)] }).unwrap();
+    let values = unhandled.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(values, Message::new("/c_setn", vec![Datum::Int(5), Datum::Int(1), Datum::Float(0.0)]));
+    let n_go = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(n_go.l_datum, vec![Datum::Int(1000)]);
+}
+
+#[test]
+fn test_status_watch() {
+    let fake = FakeScsynth::new();
+    let options = ServerOptions {
+        rx_timeout: Duration::from_millis(200),
+        ..fake_options(&fake)
+    };
+    let server = Server::new(options).unwrap();
+    server.notify().unwrap();
+    let node = server.next_node_id();
+    assert_eq!(server.status_info().unwrap().groups, 2);
+    assert_eq!(server.status().unwrap().synthdefs, 1);
+    let (tx, rx) = channel();
+    let _watch = server.watch(Duration::from_millis(20), move |event| { tx.send(event).unwrap(); });
+    let next = || rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    match next() {
+        ServerEvent::Status(info) => assert_eq!(info.groups, 2),
+        other => panic!("{:?}", other),
+    }
+    fake.alive.store(false, Ordering::SeqCst);
+    loop {
+        match next() {
+            ServerEvent::Status(_) => continue,
+            ServerEvent::Lost => break,
+            other => panic!("{:?}", other),
+        }
+    }
+    fake.alive.store(true, Ordering::SeqCst);
+    match next() {
+        ServerEvent::Restarted => {}
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(server.client_id(), 2);
+    //the old nodes are gone, so their ids are handed out again
+    assert_eq!(server.next_node_id(), node);
+}
+
+#[test]
+fn test_status_watch_no_restart() {
+    let fake = FakeScsynth::new();
+    let options = ServerOptions {
+        rx_timeout: Duration::from_millis(200),
+        ..fake_options(&fake)
+    };
+    let server = Server::new(options).unwrap();
+    server.notify().unwrap();
+    let node = server.next_node_id();
+    let bufnum = server.alloc_buffers(1).unwrap();
+    let (tx, rx) = channel();
+    let _watch = server.watch(Duration::from_millis(20), move |event| { tx.send(event).unwrap(); });
+    let next = || rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    match next() {
+        ServerEvent::Status(info) => assert_eq!(info.synthdefs, 1),
+        other => panic!("{:?}", other),
+    }
+    server.send_command(&ServerCommand::DFree { names: vec!["default".to_string()] }).unwrap();
+    fake.missed_status.store(1, Ordering::SeqCst);
+    loop {
+        match next() {
+            ServerEvent::Status(_) => continue,
+            ServerEvent::Lost => break,
+            other => panic!("{:?}", other),
+        }
+    }
+    match next() {
+        ServerEvent::Status(info) => assert_eq!(info.synthdefs, 0),
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(server.next_node_id(), node + 1);
+    assert_eq!(server.alloc_buffers(1).unwrap(), bufnum + 1);
+}
+
+#[test]
+fn test_send_synthdef() {
+    let options = ServerOptions {
+        max_def_size: 4,
+        ..fake_options(&FakeScsynth::new())
+    };
+    let server = Server::new(options).unwrap();
+    let completion = Packet::Message(Message::new("/sync", vec![Datum::Int(1)]));
+    let small = SynthDef::from_data("small", vec![1, 2, 3, 4]);
+    assert_eq!(server.send_synthdef_then(&small, completion.clone()).unwrap(), DefTransfer::Recv);
+    server.sync().unwrap();
+    assert_eq!(server.send_synthdef_then(&small, completion.clone()).unwrap(), DefTransfer::Cached);
+    //a def the server refused is sent again next time
+    let bad = SynthDef::from_data("bad", vec![]);
+    match server.send_synthdef(&bad) {
+        Err(ServerError::Failed { command, .. }) => assert_eq!(command, "/d_recv"),
+        other => panic!("expected /fail, got {:?}", other),
+    }
+    assert_eq!(server.send_synthdef_then(&bad, completion.clone()).unwrap(), DefTransfer::Recv);
+    let big = SynthDef::from_data("big/def", vec![1, 2, 3, 4, 5]);
+    let big_path = match server.send_synthdef_then(&big, completion.clone()).unwrap() {
+        DefTransfer::Load(path) => path,
+        other => panic!("{:?}", other),
+    };
+    assert!(big_path.to_string_lossy().ends_with("-big_def.scsyndef"));
+    //the fake only loads files that are there, and once it has the file is removed
+    server.sync().unwrap();
+    assert!(server.has_synthdef(&big));
+    assert!(!big_path.exists());
+    match server.send_synthdef(&SynthDef::from_data("bigger", vec![1, 2, 3, 4, 5, 6])).unwrap() {
+        DefTransfer::Load(path) => assert!(!path.exists()),
+        other => panic!("{:?}", other),
+    }
+    //another Server writes its own files, kept while its silent fake has not answered
+    let fake = FakeScsynth::new();
+    fake.alive.store(false, Ordering::SeqCst);
+    let other = Server::new(ServerOptions { max_def_size: 4, ..fake_options(&fake) }).unwrap();
+    let other_path = match other.send_synthdef_then(&big, completion.clone()).unwrap() {
+        DefTransfer::Load(path) => path,
+        other => panic!("{:?}", other),
+    };
+    assert_ne!(other_path, big_path);
+    assert_eq!(fs::read(&other_path).unwrap(), vec![1, 2, 3, 4, 5]);
+    //same name, new content
+    let small = SynthDef::from_data("small", vec![4, 3, 2, 1]);
+    assert_eq!(server.send_synthdef_then(&small, completion).unwrap(), DefTransfer::Recv);
+    server.sync().unwrap();
+    assert!(!server.has_synthdef(&bad));
+    assert_eq!(server.synthdef_names(), vec!["big/def".to_string(), "bigger".to_string(), "small".to_string()]);
+    drop(other);
+    assert!(!other_path.exists());
+}
+
+#[test]
+fn test_pending_synthdefs() {
+    let fake = FakeScsynth::new();
+    let options = ServerOptions {
+        rx_timeout: Duration::from_millis(300),
+        ..fake_options(&fake)
+    };
+    let server = Server::new(options).unwrap();
+    let def = SynthDef::from_data("def", vec![1, 2, 3, 4]);
+    let first = Packet::Message(Message::new("/first", vec![]));
+    let second = Packet::Message(Message::new("/second", vec![]));
+    //a second call while the def is still on its way does not send it again
+    fake.recv_delay.store(100, Ordering::SeqCst);
+    assert_eq!(server.send_synthdef_then(&def, first).unwrap(), DefTransfer::Recv);
+    assert_eq!(server.send_synthdef_then(&def, second).unwrap(), DefTransfer::Recv);
+    server.sync().unwrap();
+    assert!(server.has_synthdef(&def));
+    let names: Vec<String> = (0..4).map(|_| fake.next_message().name).collect();
+    assert_eq!(names, vec!["/d_recv", "/sync", "/sync", "/second"]);
+    //a def whose replies got lost is sent again once rx_timeout has passed
+    fake.recv_delay.store(0, Ordering::SeqCst);
+    let lost = SynthDef::from_data("lost", vec![4, 3, 2, 1]);
+    fake.alive.store(false, Ordering::SeqCst);
+    let third = Packet::Message(Message::new("/third", vec![]));
+    assert_eq!(server.send_synthdef_then(&lost, third).unwrap(), DefTransfer::Recv);
+    sleep(Duration::from_millis(400));
+    fake.alive.store(true, Ordering::SeqCst);
+    assert_eq!(server.send_synthdef(&lost).unwrap(), DefTransfer::Recv);
+    assert!(server.has_synthdef(&lost));
+    //the completion went with the lost def
+    let names: Vec<String> = (0..4).map(|_| fake.next_message().name).collect();
+    assert_eq!(names, vec!["/d_recv", "/sync", "/d_recv", "/sync"]);
+}
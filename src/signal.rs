@@ -0,0 +1,125 @@
+use std::f32::consts::PI;
+
+//A table of samples computed on the client, filled the same way /b_gen's sine
+//and cheby routines fill a buffer.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Signal {
+    data: Vec<f32>,
+}
+
+impl Signal {
+    pub fn new(size: usize) -> Signal {
+        Signal { data: vec![0.0; size] }
+    }
+
+    pub fn from_vec(data: Vec<f32>) -> Signal {
+        Signal { data: data }
+    }
+
+    //harmonics 1, 2, 3... like sine1
+    pub fn sine_fill(size: usize, amps: &[f32]) -> Signal {
+        let mut partials = Vec::new();
+        for (i, amp) in amps.iter().enumerate() {
+            partials.push(((i + 1) as f32, *amp, 0.0));
+        }
+        Signal::sine3_fill(size, &partials)
+    }
+
+    //(frequency, amplitude) pairs like sine2
+    pub fn sine2_fill(size: usize, partials: &[(f32, f32)]) -> Signal {
+        let partials: Vec<(f32, f32, f32)> = partials.iter().map(|&(freq, amp)| (freq, amp, 0.0)).collect();
+        Signal::sine3_fill(size, &partials)
+    }
+
+    //(frequency, amplitude, phase) triples like sine3, frequencies in cycles per table
+    pub fn sine3_fill(size: usize, partials: &[(f32, f32, f32)]) -> Signal {
+        let mut signal = Signal::new(size);
+        for &(freq, amp, phase) in partials {
+            for (i, x) in signal.data.iter_mut().enumerate() {
+                *x += amp * (2.0 * PI * freq * i as f32 / size as f32 + phase).sin();
+            }
+        }
+        signal
+    }
+
+    //Sum of Chebyshev polynomials T1, T2... over -1 to 1, the transfer function
+    //for waveshaping with Shaper
+    pub fn cheby_fill(size: usize, amps: &[f32]) -> Signal {
+        let mut signal = Signal::new(size);
+        let step = if size > 1 { 2.0 / (size - 1) as f32 } else { 0.0 };
+        for (i, x) in signal.data.iter_mut().enumerate() {
+            let pos = (-1.0 + step * i as f32).max(-1.0).min(1.0);
+            for (k, amp) in amps.iter().enumerate() {
+                *x += amp * ((k + 1) as f32 * pos.acos()).cos();
+            }
+        }
+        signal
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn data(&self) -> &[f32] {
+        &self.data
+    }
+
+    pub fn into_vec(self) -> Vec<f32> {
+        self.data
+    }
+
+    pub fn peak(&self) -> f32 {
+        self.data.iter().fold(0.0, |peak, x| peak.max(x.abs()))
+    }
+
+    //scales the signal so its peak is 1, silence stays silent
+    pub fn normalize(&mut self) {
+        let peak = self.peak();
+        if peak > 0.0 {
+            for x in self.data.iter_mut() {
+                *x /= peak;
+            }
+        }
+    }
+
+    //The interleaved format Osc and VOsc read, twice the length of the signal.
+    //Each pair holds 2a - b and b - a for neighbours a and b, wrapping at the end.
+    pub fn as_wavetable(&self) -> Vec<f32> {
+        let size = self.data.len();
+        let mut out = Vec::with_capacity(size * 2);
+        for i in 0..size {
+            let (a, b) = (self.data[i], self.data[(i + 1) % size]);
+            out.push(2.0 * a - b);
+            out.push(b - a);
+        }
+        out
+    }
+
+    //Same as as_wavetable without the wrap, for Shaper. A signal of 2^n + 1
+    //points gives a 2^(n + 1) table.
+    pub fn as_wavetable_no_wrap(&self) -> Vec<f32> {
+        let mut out = Vec::new();
+        for pair in self.data.windows(2) {
+            out.push(2.0 * pair[0] - pair[1]);
+            out.push(pair[1] - pair[0]);
+        }
+        out
+    }
+}
+
+#[test]
+fn test_signal() {
+    let sine = Signal::sine_fill(4, &[1.0]);
+    let expected = [0.0, 1.0, 0.0, -1.0];
+    for (x, y) in sine.data().iter().zip(expected.iter()) {
+        assert!((x - y).abs() < 1e-6);
+    }
+    let table = Signal::from_vec(vec![0.0, 1.0, 0.5]).as_wavetable();
+    assert_eq!(table, vec![-1.0, 1.0, 1.5, -0.5, 1.0, -0.5]);
+    assert_eq!(Signal::from_vec(vec![0.0, 1.0, 0.5]).as_wavetable_no_wrap(), vec![-1.0, 1.0, 1.5, -0.5]);
+    //T2(x) = 2x^2 - 1
+    let mut cheby = Signal::cheby_fill(3, &[0.0, 0.5]);
+    assert_eq!(cheby.data(), &[0.5, -0.5, 0.5]);
+    cheby.normalize();
+    assert_eq!(cheby.peak(), 1.0);
+}
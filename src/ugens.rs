@@ -1,4 +1,4 @@
-use osc::{sc_play, sc_play_vec};
+use server::{sc_play, sc_play_vec, Server};
 use sc3::*;
 use std::ops::{Add, Mul, Sub};
 
@@ -57,6 +57,11 @@ fn const_vec(nums: Vec<f32>) -> UgenList {
     out
 }
 
+//a named kr control with a default value, set per node with Synth::set
+pub fn control(name: &str, default: f32) -> Ugen {
+    mk_control(Rate::RateKr, name, default)
+}
+
 pub fn sin_osc(freq: f32, phase: f32) -> Ugen {
     let osc = Oscillator::new("SinOsc", freq, phase);
     osc.run(1)
@@ -83,6 +88,16 @@ pub fn out(a: i32, ugen: &Ugen) -> Ugen {
     return mk_filter_mce("Out", iconst_list(a), ugen, 0);
 }
 
+//like out, but the bus is a ugen so it can be a control set per synth
+pub fn out_to(bus: Ugen, ugen: &Ugen) -> Ugen {
+    mk_filter_mce("Out", vec![Box::new(bus)], ugen, 0)
+}
+
+//reads num_channels consecutive buses starting at bus
+pub fn in_bus(rate: Rate, bus: Ugen, num_channels: i32) -> Ugen {
+    mk_oscillator(rate, "In", vec![Box::new(bus)], num_channels)
+}
+
 pub fn brown_noise() -> Ugen {
     return mk_osc_id(Rate::RateAr, "BrownNoise", vec![], 1);
 }
@@ -110,6 +125,311 @@ pub fn rhpf(ugen1: Ugen, ugen2: Ugen, coef: f32) -> Ugen {
     );
 }
 
+fn ugen_vec(ugens: Vec<Ugen>) -> UgenList {
+    let mut out = Vec::new();
+    for elem in ugens {
+        out.push(Box::new(elem));
+    }
+    out
+}
+
+//Buffer playback and recording
+
+pub fn play_buf(rate: Rate, num_channels: i32, bufnum: Ugen, rate_scale: Ugen, trigger: Ugen,
+                start_pos: Ugen, looping: Ugen, done_action: Ugen) -> Ugen {
+    let inputs = ugen_vec(vec![bufnum, rate_scale, trigger, start_pos, looping, done_action]);
+    mk_oscillator(rate, "PlayBuf", inputs, num_channels)
+}
+
+pub fn record_buf(rate: Rate, input: &Ugen, bufnum: Ugen, offset: Ugen, rec_level: Ugen,
+                  pre_level: Ugen, run: Ugen, looping: Ugen, trigger: Ugen, done_action: Ugen) -> Ugen {
+    let inputs = ugen_vec(vec![bufnum, offset, rec_level, pre_level, run, looping, trigger, done_action]);
+    mk_osc_mce(rate, "RecordBuf", inputs, input, 1)
+}
+
+pub fn buf_rd(rate: Rate, num_channels: i32, bufnum: Ugen, phase: Ugen, looping: Ugen,
+              interpolation: Ugen) -> Ugen {
+    let inputs = ugen_vec(vec![bufnum, phase, looping, interpolation]);
+    mk_oscillator(rate, "BufRd", inputs, num_channels)
+}
+
+pub fn buf_wr(rate: Rate, input: &Ugen, bufnum: Ugen, phase: Ugen, looping: Ugen) -> Ugen {
+    let inputs = ugen_vec(vec![bufnum, phase, looping]);
+    mk_osc_mce(rate, "BufWr", inputs, input, 1)
+}
+
+pub fn grain_buf(num_channels: i32, trigger: Ugen, dur: Ugen, sndbuf: Ugen, rate_scale: Ugen,
+                 pos: Ugen, interp: Ugen, pan: Ugen, envbufnum: Ugen, max_grains: Ugen) -> Ugen {
+    let inputs = ugen_vec(vec![trigger, dur, sndbuf, rate_scale, pos, interp, pan, envbufnum, max_grains]);
+    mk_oscillator(Rate::RateAr, "GrainBuf", inputs, num_channels)
+}
+
+pub fn t_grains(num_channels: i32, trigger: Ugen, bufnum: Ugen, rate_scale: Ugen, center_pos: Ugen,
+                dur: Ugen, pan: Ugen, amp: Ugen, interp: Ugen) -> Ugen {
+    let inputs = ugen_vec(vec![trigger, bufnum, rate_scale, center_pos, dur, pan, amp, interp]);
+    mk_oscillator(Rate::RateAr, "TGrains", inputs, num_channels)
+}
+
+pub fn warp1(num_channels: i32, bufnum: Ugen, pointer: Ugen, freq_scale: Ugen, window_size: Ugen,
+             envbufnum: Ugen, overlaps: Ugen, window_rand_ratio: Ugen, interp: Ugen) -> Ugen {
+    let inputs = ugen_vec(vec![bufnum, pointer, freq_scale, window_size, envbufnum, overlaps,
+                               window_rand_ratio, interp]);
+    mk_oscillator(Rate::RateAr, "Warp1", inputs, num_channels)
+}
+
+//Buffer info, valid at RateIr and RateKr
+
+pub fn buf_frames(rate: Rate, bufnum: Ugen) -> Ugen {
+    mk_oscillator(rate, "BufFrames", ugen_vec(vec![bufnum]), 1)
+}
+
+pub fn buf_sample_rate(rate: Rate, bufnum: Ugen) -> Ugen {
+    mk_oscillator(rate, "BufSampleRate", ugen_vec(vec![bufnum]), 1)
+}
+
+pub fn buf_rate_scale(rate: Rate, bufnum: Ugen) -> Ugen {
+    mk_oscillator(rate, "BufRateScale", ugen_vec(vec![bufnum]), 1)
+}
+
+pub fn buf_dur(rate: Rate, bufnum: Ugen) -> Ugen {
+    mk_oscillator(rate, "BufDur", ugen_vec(vec![bufnum]), 1)
+}
+
+pub fn buf_channels(rate: Rate, bufnum: Ugen) -> Ugen {
+    mk_oscillator(rate, "BufChannels", ugen_vec(vec![bufnum]), 1)
+}
+
+//Spectral processing, chains run at RateKr
+
+pub fn local_buf(num_frames: Ugen, num_channels: Ugen) -> Ugen {
+    //MaxLocalBufs is appended to the inputs by synth()
+    mk_osc_id(Rate::RateIr, "LocalBuf", ugen_vec(vec![num_channels, num_frames]), 1)
+}
+
+pub fn fft(buffer: Ugen, input: Ugen, hop: Ugen, wintype: Ugen, active: Ugen, winsize: Ugen) -> Ugen {
+    let inputs = ugen_vec(vec![buffer, input, hop, wintype, active, winsize]);
+    mk_oscillator(Rate::RateKr, "FFT", inputs, 1)
+}
+
+pub fn ifft(rate: Rate, buffer: Ugen, wintype: Ugen, winsize: Ugen) -> Ugen {
+    mk_oscillator(rate, "IFFT", ugen_vec(vec![buffer, wintype, winsize]), 1)
+}
+
+fn pv(name: &str, inputs: Vec<Ugen>) -> Ugen {
+    mk_oscillator(Rate::RateKr, name, ugen_vec(inputs), 1)
+}
+
+pub fn pv_mag_above(buffer: Ugen, threshold: Ugen) -> Ugen {
+    pv("PV_MagAbove", vec![buffer, threshold])
+}
+
+pub fn pv_mag_below(buffer: Ugen, threshold: Ugen) -> Ugen {
+    pv("PV_MagBelow", vec![buffer, threshold])
+}
+
+pub fn pv_mag_clip(buffer: Ugen, threshold: Ugen) -> Ugen {
+    pv("PV_MagClip", vec![buffer, threshold])
+}
+
+pub fn pv_local_max(buffer: Ugen, threshold: Ugen) -> Ugen {
+    pv("PV_LocalMax", vec![buffer, threshold])
+}
+
+pub fn pv_brick_wall(buffer: Ugen, wipe: Ugen) -> Ugen {
+    pv("PV_BrickWall", vec![buffer, wipe])
+}
+
+pub fn pv_mag_freeze(buffer: Ugen, freeze: Ugen) -> Ugen {
+    pv("PV_MagFreeze", vec![buffer, freeze])
+}
+
+pub fn pv_phase_shift(buffer: Ugen, shift: Ugen) -> Ugen {
+    pv("PV_PhaseShift", vec![buffer, shift])
+}
+
+pub fn pv_rand_comb(buffer: Ugen, wipe: Ugen, trig: Ugen) -> Ugen {
+    mk_osc_id(Rate::RateKr, "PV_RandComb", ugen_vec(vec![buffer, wipe, trig]), 1)
+}
+
+pub fn pv_copy(buffer_a: Ugen, buffer_b: Ugen) -> Ugen {
+    pv("PV_Copy", vec![buffer_a, buffer_b])
+}
+
+pub fn pv_mul(buffer_a: Ugen, buffer_b: Ugen) -> Ugen {
+    pv("PV_Mul", vec![buffer_a, buffer_b])
+}
+
+pub fn pv_add(buffer_a: Ugen, buffer_b: Ugen) -> Ugen {
+    pv("PV_Add", vec![buffer_a, buffer_b])
+}
+
+pub fn pv_max(buffer_a: Ugen, buffer_b: Ugen) -> Ugen {
+    pv("PV_Max", vec![buffer_a, buffer_b])
+}
+
+pub fn pv_min(buffer_a: Ugen, buffer_b: Ugen) -> Ugen {
+    pv("PV_Min", vec![buffer_a, buffer_b])
+}
+
+pub fn pv_mag_mul(buffer_a: Ugen, buffer_b: Ugen) -> Ugen {
+    pv("PV_MagMul", vec![buffer_a, buffer_b])
+}
+
+//Demand rate. Sequence ugens are stateful, so each call gets its own id.
+
+pub fn demand(rate: Rate, trig: Ugen, reset: Ugen, demand_ugens: &Ugen) -> Ugen {
+    let ou = match demand_ugens {
+        Ugen::Mce(mce) => mce.ugens.len() as i32,
+        _ => 1,
+    };
+    mk_osc_mce(rate, "Demand", ugen_vec(vec![trig, reset]), demand_ugens, ou)
+}
+
+pub fn duty(rate: Rate, dur: Ugen, reset: Ugen, level: Ugen, done_action: Ugen) -> Ugen {
+    mk_oscillator(rate, "Duty", ugen_vec(vec![dur, reset, done_action, level]), 1)
+}
+
+pub fn t_duty(rate: Rate, dur: Ugen, reset: Ugen, level: Ugen, done_action: Ugen, gap_first: Ugen) -> Ugen {
+    mk_oscillator(rate, "TDuty", ugen_vec(vec![dur, reset, done_action, level, gap_first]), 1)
+}
+
+pub fn demand_env_gen(rate: Rate, level: Ugen, dur: Ugen, shape: Ugen, curve: Ugen, gate: Ugen,
+                      reset: Ugen, level_scale: Ugen, level_bias: Ugen, time_scale: Ugen,
+                      done_action: Ugen) -> Ugen {
+    let inputs = ugen_vec(vec![level, dur, shape, curve, gate, reset, level_scale, level_bias,
+                               time_scale, done_action]);
+    mk_oscillator(rate, "DemandEnvGen", inputs, 1)
+}
+
+pub fn dseq(list: &Ugen, repeats: Ugen) -> Ugen {
+    mk_osc_mce_id(Rate::RateDr, "Dseq", ugen_vec(vec![repeats]), list, 1)
+}
+
+pub fn drand(list: &Ugen, repeats: Ugen) -> Ugen {
+    mk_osc_mce_id(Rate::RateDr, "Drand", ugen_vec(vec![repeats]), list, 1)
+}
+
+pub fn dxrand(list: &Ugen, repeats: Ugen) -> Ugen {
+    mk_osc_mce_id(Rate::RateDr, "Dxrand", ugen_vec(vec![repeats]), list, 1)
+}
+
+pub fn dswitch(list: &Ugen, index: Ugen) -> Ugen {
+    mk_osc_mce_id(Rate::RateDr, "Dswitch", ugen_vec(vec![index]), list, 1)
+}
+
+pub fn dseries(start: Ugen, step: Ugen, length: Ugen) -> Ugen {
+    mk_osc_id(Rate::RateDr, "Dseries", ugen_vec(vec![length, start, step]), 1)
+}
+
+pub fn dgeom(start: Ugen, grow: Ugen, length: Ugen) -> Ugen {
+    mk_osc_id(Rate::RateDr, "Dgeom", ugen_vec(vec![length, start, grow]), 1)
+}
+
+pub fn dwhite(lo: Ugen, hi: Ugen, length: Ugen) -> Ugen {
+    mk_osc_id(Rate::RateDr, "Dwhite", ugen_vec(vec![length, lo, hi]), 1)
+}
+
+pub fn dbrown(lo: Ugen, hi: Ugen, step: Ugen, length: Ugen) -> Ugen {
+    mk_osc_id(Rate::RateDr, "Dbrown", ugen_vec(vec![length, lo, hi, step]), 1)
+}
+
+pub fn dstutter(n: Ugen, input: Ugen) -> Ugen {
+    mk_osc_id(Rate::RateDr, "Dstutter", ugen_vec(vec![n, input]), 1)
+}
+
+//Analysis and dynamics
+
+pub fn amplitude(rate: Rate, input: Ugen, attack_time: Ugen, release_time: Ugen) -> Ugen {
+    mk_oscillator(rate, "Amplitude", ugen_vec(vec![input, attack_time, release_time]), 1)
+}
+
+//outputs are frequency and hasFreq
+pub fn pitch(input: Ugen, init_freq: Ugen, min_freq: Ugen, max_freq: Ugen, exec_freq: Ugen,
+             max_bins_per_octave: Ugen, median: Ugen, amp_threshold: Ugen, peak_threshold: Ugen,
+             down_sample: Ugen, clar: Ugen) -> Ugen {
+    let inputs = ugen_vec(vec![input, init_freq, min_freq, max_freq, exec_freq, max_bins_per_octave,
+                               median, amp_threshold, peak_threshold, down_sample, clar]);
+    mk_oscillator(Rate::RateKr, "Pitch", inputs, 2)
+}
+
+pub fn zero_crossing(input: Ugen) -> Ugen {
+    mk_filter("ZeroCrossing", ugen_vec(vec![input]), 1)
+}
+
+pub fn running_sum(input: Ugen, numsamp: Ugen) -> Ugen {
+    mk_filter("RunningSum", ugen_vec(vec![input, numsamp]), 1)
+}
+
+pub fn peak(input: Ugen, trig: Ugen) -> Ugen {
+    mk_filter("Peak", ugen_vec(vec![input, trig]), 1)
+}
+
+pub fn peak_follower(input: Ugen, decay: Ugen) -> Ugen {
+    mk_filter("PeakFollower", ugen_vec(vec![input, decay]), 1)
+}
+
+pub fn rms(input: Ugen, lp_freq: Ugen) -> Ugen {
+    mk_filter("RMS", ugen_vec(vec![input, lp_freq]), 1)
+}
+
+pub fn onsets(chain: Ugen, threshold: Ugen, odftype: Ugen, relaxtime: Ugen, floor: Ugen, mingap: Ugen,
+              medianspan: Ugen, whtype: Ugen, rawodf: Ugen) -> Ugen {
+    let inputs = ugen_vec(vec![chain, threshold, odftype, relaxtime, floor, mingap, medianspan,
+                               whtype, rawodf]);
+    mk_oscillator(Rate::RateKr, "Onsets", inputs, 1)
+}
+
+pub fn loudness(chain: Ugen, smask: Ugen, tmask: Ugen) -> Ugen {
+    mk_oscillator(Rate::RateKr, "Loudness", ugen_vec(vec![chain, smask, tmask]), 1)
+}
+
+pub fn compander(input: Ugen, control: Ugen, thresh: Ugen, slope_below: Ugen, slope_above: Ugen,
+                 clamp_time: Ugen, relax_time: Ugen) -> Ugen {
+    let inputs = ugen_vec(vec![input, control, thresh, slope_below, slope_above, clamp_time, relax_time]);
+    mk_filter("Compander", inputs, 1)
+}
+
+pub fn limiter(input: Ugen, level: Ugen, dur: Ugen) -> Ugen {
+    mk_filter("Limiter", ugen_vec(vec![input, level, dur]), 1)
+}
+
+pub fn normalizer(input: Ugen, level: Ugen, dur: Ugen) -> Ugen {
+    mk_filter("Normalizer", ugen_vec(vec![input, level, dur]), 1)
+}
+
+//Strings are passed to the server as a length followed by one constant per character
+fn ascii_inputs(text: &str) -> Vec<Ugen> {
+    let mut out = vec![c(text.len() as f64)];
+    for byte in text.bytes() {
+        out.push(c(byte as f64));
+    }
+    out
+}
+
+//SendTrig and SendReply have no outputs, keep them in the graph with mrg
+pub fn send_trig(rate: Rate, input: Ugen, id: Ugen, value: Ugen) -> Ugen {
+    mk_oscillator(rate, "SendTrig", ugen_vec(vec![input, id, value]), 0)
+}
+
+pub fn send_reply(rate: Rate, trig: Ugen, cmd_name: &str, values: &Ugen, reply_id: Ugen) -> Ugen {
+    let mut inputs = vec![trig, reply_id];
+    inputs.extend(ascii_inputs(cmd_name));
+    mk_osc_mce(rate, "SendReply", ugen_vec(inputs), values, 0)
+}
+
+pub fn poll(rate: Rate, trig: Ugen, input: Ugen, label: &str, trig_id: Ugen) -> Ugen {
+    let mut inputs = vec![trig, input, trig_id];
+    inputs.extend(ascii_inputs(label));
+    mk_oscillator(rate, "Poll", ugen_vec(inputs), 1)
+}
+
+pub fn mrg(left: Ugen, right: Ugen) -> Ugen {
+    Ugen::Mrg(Mrg {
+        left: Box::new(left),
+        right: Box::new(right),
+    })
+}
+
 use std::any::Any;
 
 //Ugenize a float
@@ -145,13 +465,17 @@ pub fn bubbles() -> Ugen{
     let ug2 = c(4.0) * (c(1.003) * ug0 + c(1.005) * ug1);
     ug2
 }
-pub fn play_demo_1() {
-    //sc_play(&sin_osc(440.0, 0.0));
-    sc_play(&bubbles());
+pub fn play_demo_1(server: &Server) {
+    //sc_play(server, &sin_osc(440.0, 0.0));
+    if let Err(err) = sc_play(server, &bubbles()) {
+        println!("play_demo_1: {}", err);
+    }
 }
 
-pub fn play_demo_2() {
-    sc_play_vec(vec![bubbles(), bubbles()]);
+pub fn play_demo_2(server: &Server) {
+    if let Err(err) = sc_play_vec(server, vec![bubbles(), bubbles()]) {
+        println!("play_demo_2: {}", err);
+    }
     //sc_play_vec(vec![mul(sin_osc(440.0, 0.0), 0.1), mul(sin_osc(100.0, 0.0), 0.1)]);
 }
 
@@ -178,3 +502,24 @@ macro_rules! sin_osc_m {
 fn test2() {
     assert_eq!(true, true);
 }
+
+#[test]
+fn test_play_buf_channels() {
+    let pb = play_buf(Rate::RateAr, 2, c(0.0), c(1.0), c(1.0), c(0.0), c(1.0), c(0.0));
+    match pb {
+        Ugen::Mce(mce) => assert_eq!(mce.ugens.len(), 2),
+        _ => panic!("play_buf channels"),
+    }
+    //inputs in sclang's order: bufnum, rate, trigger, startPos, loop, doneAction
+    let pb = play_buf(Rate::RateAr, 1, c(10.0), c(11.0), c(12.0), c(13.0), c(14.0), c(15.0));
+    let inputs = ugen_vec(vec![c(10.0), c(11.0), c(12.0), c(13.0), c(14.0), c(15.0)]);
+    assert_eq!(pb, mk_oscillator(Rate::RateAr, "PlayBuf", inputs, 1));
+    //bufnum, phase, loop, interpolation
+    let rd = buf_rd(Rate::RateAr, 1, c(10.0), c(11.0), c(12.0), c(13.0));
+    let inputs = ugen_vec(vec![c(10.0), c(11.0), c(12.0), c(13.0)]);
+    assert_eq!(rd, mk_oscillator(Rate::RateAr, "BufRd", inputs, 1));
+    match buf_rd(Rate::RateAr, 1, c(0.0), c(0.0), c(1.0), c(2.0)) {
+        Ugen::Primitive(_) => {},
+        _ => panic!("buf_rd channels"),
+    }
+}